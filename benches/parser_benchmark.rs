@@ -16,7 +16,7 @@ fn parse_benchmark(c: &mut Criterion) {
 
             if path.extension().and_then(|s| s.to_str()) == Some("tja") {
                 let filename = path.file_name().unwrap().to_string_lossy().into_owned();
-                let content = fs::read_to_string(&path).expect("Failed to read file");
+                let content = fs::read(&path).expect("Failed to read file");
                 Some((filename, content))
             } else {
                 None
@@ -31,13 +31,13 @@ fn parse_benchmark(c: &mut Criterion) {
         (ParsingMode::Full, "full"),
     ];
 
-    // Benchmark each file with each parsing mode
+    // Benchmark each file with each parsing mode, decoding included
     for (filename, content) in tja_files {
         for (mode, mode_name) in &modes {
             group.bench_function(format!("{} - {}", mode_name, filename), |b| {
                 b.iter(|| {
                     let mut parser = TJAParser::with_mode(mode.clone());
-                    parser.parse_str(black_box(&content)).unwrap();
+                    parser.parse_bytes(black_box(&content)).unwrap();
                 });
             });
         }
@@ -1,8 +1,7 @@
 use std::collections::HashMap;
 use std::env;
-use std::fs;
 use std::process;
-use tja::TJAParser;
+use tja::{TJAParser, TjaError};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -13,24 +12,20 @@ fn main() {
 
     let file_path = &args[1];
 
-    match fs::read_to_string(file_path) {
-        Ok(content) => {
-            let mut parser = TJAParser::new();
-            match parser.parse_str(&content) {
-                Ok(()) => {
-                    let parsed = parser.get_parsed_tja();
-                    analyze_tja(&parsed);
-                }
-                Err(e) => {
-                    eprintln!("Error parsing TJA file: {}", e);
-                    process::exit(1);
-                }
-            }
+    let mut parser = TJAParser::new();
+    match parser.parse_file(file_path) {
+        Ok(()) => {
+            let parsed = parser.get_parsed_tja();
+            analyze_tja(&parsed);
         }
-        Err(e) => {
+        Err(TjaError::Io(e)) => {
             eprintln!("Error reading file {}: {}", file_path, e);
             process::exit(1);
         }
+        Err(e) => {
+            eprintln!("Error parsing TJA file: {}", e);
+            process::exit(1);
+        }
     }
 }
 
@@ -47,7 +42,7 @@ fn analyze_tja(parsed: &tja::ParsedTJA) {
     }
 
     let oni_course = oni_course.unwrap();
-    let notes = tja::filter_notes(oni_course, Some(&"M"));
+    let notes = tja::filter_notes(oni_course, Some("M"));
 
     let mut gaps = HashMap::<u64, u64>::new();
 
@@ -70,7 +65,7 @@ fn analyze_tja(parsed: &tja::ParsedTJA) {
     while unit < smallest_gap {
         let mut should_stop = false;
 
-        for (gap, _) in &gaps {
+        for gap in gaps.keys() {
             println!("Checking gap: {} (unit: {})", gap, unit);
             let gap_unit = (gap + unit / 2) / unit;
             let reconstructed_gap = gap_unit * unit;
@@ -13,7 +13,7 @@ fn main() {
             let path = entry.path();
 
             if path.extension().and_then(|s| s.to_str()) == Some("tja") {
-                let content = fs::read_to_string(&path).expect("Failed to read file");
+                let content = fs::read(&path).expect("Failed to read file");
                 Some((path, content))
             } else {
                 None
@@ -29,7 +29,7 @@ fn main() {
             for _ in 0..10 {
                 // Parse each file 10 times
                 let mut parser = TJAParser::new();
-                parser.parse_str(content).expect("Failed to parse");
+                parser.parse_bytes(content).expect("Failed to parse");
             }
         }
     }
@@ -1,6 +1,5 @@
 use hound;
 use std::env;
-use std::fs;
 use std::path::Path;
 use std::process;
 use std::str::FromStr;
@@ -10,7 +9,7 @@ use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta::MetadataOptions;
 use tja::ParsedTJA;
-use tja::{synthesize_tja_audio, AudioData, Course, TJAParser};
+use tja::{synthesize_tja_audio, AudioData, Course, TJAParser, TjaError};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -62,18 +61,17 @@ fn main() {
     }
 
     // Parse TJA file
-    let tja_content = match fs::read_to_string(tja_path) {
-        Ok(content) => content,
-        Err(e) => {
+    let mut parser = TJAParser::new();
+    match parser.parse_file(tja_path) {
+        Ok(()) => {}
+        Err(TjaError::Io(e)) => {
             eprintln!("Error reading TJA file {}: {}", tja_path, e);
             process::exit(1);
         }
-    };
-
-    let mut parser = TJAParser::new();
-    if let Err(e) = parser.parse_str(&tja_content) {
-        eprintln!("Error parsing TJA file: {}", e);
-        process::exit(1);
+        Err(e) => {
+            eprintln!("Error parsing TJA file: {}", e);
+            process::exit(1);
+        }
     }
 
     let parsed = parser.get_parsed_tja();
@@ -1,35 +1,113 @@
 use std::env;
 use std::fs;
 use std::process;
-use tja::TJAParser;
+use tja::{format_tja, FormatOptions, NoteResolution, TJAParser, TextEncoding, TjaError};
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} <tja_file>", program);
+    eprintln!(
+        "       {} fmt [--check] [--extended-notes] [--minimal | --resolution <n>] <tja_file>...",
+        program
+    );
+    process::exit(1);
+}
+
+fn fmt(program: &str, args: &[String]) {
+    let mut check = false;
+    let mut options = FormatOptions::default();
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--extended-notes" => options.extended_notes = true,
+            "--minimal" => options.resolution = NoteResolution::Minimal,
+            "--resolution" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => options.resolution = NoteResolution::Fixed(n),
+                _ => usage(program),
+            },
+            _ if arg.starts_with("--") => usage(program),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        usage(program);
+    }
+
+    let mut unformatted = false;
+    let mut failed = false;
+    for file_path in files {
+        let bytes = match fs::read(file_path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Error reading file {}: {}", file_path, e);
+                process::exit(1);
+            }
+        };
+        // Write the file back in the encoding it was read in, and leave it
+        // alone if that would replace characters that do not decode or
+        // encode cleanly
+        let encoding = TextEncoding::detect(&bytes);
+        let Some(text) = encoding.try_decode(&bytes) else {
+            eprintln!("Skipping {}: not valid {:?} text", file_path, encoding);
+            failed = true;
+            continue;
+        };
+        let formatted = format_tja(&text, &options);
+        let output = encoding.encode(&formatted);
+        if output == bytes {
+            continue;
+        }
+        if encoding.try_decode(&output).as_deref() != Some(formatted.as_str()) {
+            eprintln!(
+                "Skipping {}: formatted text cannot be written as {:?}",
+                file_path, encoding
+            );
+            failed = true;
+            continue;
+        }
+
+        if check {
+            println!("Would reformat {}", file_path);
+            unformatted = true;
+        } else if let Err(e) = fs::write(file_path, output) {
+            eprintln!("Error writing file {}: {}", file_path, e);
+            process::exit(1);
+        } else {
+            println!("Formatted {}", file_path);
+        }
+    }
+    if unformatted || failed {
+        process::exit(1);
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    if args.len() >= 2 && args[1] == "fmt" {
+        fmt(&args[0], &args[2..]);
+        return;
+    }
     if args.len() != 2 {
-        eprintln!("Usage: {} <tja_file>", args[0]);
-        process::exit(1);
+        usage(&args[0]);
     }
 
     let file_path = &args[1];
 
-    match fs::read_to_string(file_path) {
-        Ok(content) => {
-            let mut parser = TJAParser::new();
-            match parser.parse_str(&content) {
-                Ok(()) => {
-                    let parsed = parser.get_parsed_tja();
-                    let json = serde_json::to_string_pretty(&parsed).unwrap();
-                    println!("{}", json);
-                }
-                Err(e) => {
-                    eprintln!("Error parsing TJA file: {}", e);
-                    process::exit(1);
-                }
-            }
+    let mut parser = TJAParser::new();
+    match parser.parse_file(file_path) {
+        Ok(()) => {
+            let parsed = parser.get_parsed_tja();
+            let json = serde_json::to_string_pretty(&parsed).unwrap();
+            println!("{}", json);
         }
-        Err(e) => {
+        Err(TjaError::Io(e)) => {
             eprintln!("Error reading file {}: {}", file_path, e);
             process::exit(1);
         }
+        Err(e) => {
+            eprintln!("Error parsing TJA file: {}", e);
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,493 @@
+use crate::{Chart, ChartEvent, Directive, Measure, Segment};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// One of the three paths of a branched chart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Branch {
+    Normal,
+    Expert,
+    Master,
+}
+
+impl Branch {
+    /// The name used by [`Segment::branch`], e.g. `"N"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Branch::Normal => "N",
+            Branch::Expert => "E",
+            Branch::Master => "M",
+        }
+    }
+}
+
+impl std::str::FromStr for Branch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "N" => Ok(Branch::Normal),
+            "E" => Ok(Branch::Expert),
+            "M" => Ok(Branch::Master),
+            _ => Err(format!("Invalid branch: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Branch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Condition of a `#BRANCHSTART`, with the thresholds for the expert and
+/// master branches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BranchCondition {
+    /// `p`: accuracy in percent.
+    Accuracy { expert: f64, master: f64 },
+    /// `r`: number of roll hits.
+    Roll { expert: f64, master: f64 },
+    /// `s`: score.
+    Score { expert: f64, master: f64 },
+    /// Any other letter, as added by some simulators.
+    Other {
+        kind: String,
+        expert: f64,
+        master: f64,
+    },
+}
+
+impl BranchCondition {
+    pub fn expert(&self) -> f64 {
+        match self {
+            BranchCondition::Accuracy { expert, .. }
+            | BranchCondition::Roll { expert, .. }
+            | BranchCondition::Score { expert, .. }
+            | BranchCondition::Other { expert, .. } => *expert,
+        }
+    }
+
+    pub fn master(&self) -> f64 {
+        match self {
+            BranchCondition::Accuracy { master, .. }
+            | BranchCondition::Roll { master, .. }
+            | BranchCondition::Score { master, .. }
+            | BranchCondition::Other { master, .. } => *master,
+        }
+    }
+}
+
+impl std::str::FromStr for BranchCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        let [kind, expert, master] = parts[..] else {
+            return Err(format!("Invalid branch condition: {}", s));
+        };
+        let (Ok(expert), Ok(master)) = (expert.parse(), master.parse()) else {
+            return Err(format!("Invalid branch condition: {}", s));
+        };
+        match kind.to_lowercase().as_str() {
+            "p" => Ok(BranchCondition::Accuracy { expert, master }),
+            "r" => Ok(BranchCondition::Roll { expert, master }),
+            "s" => Ok(BranchCondition::Score { expert, master }),
+            "" => Err(format!("Invalid branch condition: {}", s)),
+            other => Ok(BranchCondition::Other {
+                kind: other.to_string(),
+                expert,
+                master,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for BranchCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            BranchCondition::Accuracy { .. } => "p",
+            BranchCondition::Roll { .. } => "r",
+            BranchCondition::Score { .. } => "s",
+            BranchCondition::Other { kind, .. } => kind,
+        };
+        write!(f, "{},{},{}", kind, self.expert(), self.master())
+    }
+}
+
+/// The segments of one `#BRANCHSTART` block, grouped by branch.
+#[derive(Debug, Clone)]
+pub struct BranchSection<'a> {
+    /// `None` if the `#BRANCHSTART` arguments could not be read.
+    pub condition: Option<BranchCondition>,
+    /// The arguments of `#BRANCHSTART` as written.
+    pub raw_condition: &'a str,
+    pub normal: Vec<&'a Segment>,
+    pub expert: Vec<&'a Segment>,
+    pub master: Vec<&'a Segment>,
+}
+
+impl<'a> BranchSection<'a> {
+    pub fn segments(&self, branch: Branch) -> &[&'a Segment] {
+        match branch {
+            Branch::Normal => &self.normal,
+            Branch::Expert => &self.expert,
+            Branch::Master => &self.master,
+        }
+    }
+}
+
+impl Chart {
+    /// Groups the branched segments by `#BRANCHSTART` block, in chart order.
+    ///
+    /// Measures of a block written before any of `#N`, `#E` or `#M` are
+    /// played on every branch and appear in all three lists.
+    pub fn branch_sections(&self) -> Vec<BranchSection<'_>> {
+        // Blocks without a single measure leave no trace in the segments
+        let mut sections: Vec<Option<BranchSection>> = Vec::new();
+        for segment in &self.segments {
+            let Some(index) = segment.branch_section else {
+                continue;
+            };
+            if sections.len() <= index {
+                sections.resize_with(index + 1, || None);
+            }
+            let section = sections[index].get_or_insert_with(|| {
+                let raw_condition = segment.branch_condition.as_deref().unwrap_or_default();
+                BranchSection {
+                    condition: raw_condition.parse().ok(),
+                    raw_condition,
+                    normal: Vec::new(),
+                    expert: Vec::new(),
+                    master: Vec::new(),
+                }
+            });
+            match segment.branch.as_deref().and_then(|b| b.parse().ok()) {
+                Some(Branch::Normal) => section.normal.push(segment),
+                Some(Branch::Expert) => section.expert.push(segment),
+                Some(Branch::Master) => section.master.push(segment),
+                None => {
+                    section.normal.push(segment);
+                    section.expert.push(segment);
+                    section.master.push(segment);
+                }
+            }
+        }
+        sections.into_iter().flatten().collect()
+    }
+}
+
+/// Counters a `#BRANCHSTART` condition is compared against, gained between
+/// the previous branch decision and this one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SectionStats {
+    /// Accuracy in percent over the notes judged.
+    pub accuracy: f64,
+    /// Roll hits.
+    pub rolls: u32,
+    pub score: u32,
+}
+
+/// How a player performs, for [`Chart::simulate_branches`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BranchProfile {
+    /// Always take this branch.
+    Forced(Branch),
+    /// Performance before each `#BRANCHSTART`, in chart order. Missing
+    /// entries count as zero.
+    Sections(Vec<SectionStats>),
+}
+
+impl BranchCondition {
+    /// Branch taken with `stats`, the counters accumulated since the last
+    /// reset. Conditions the crate cannot evaluate lead to the normal branch.
+    pub fn evaluate(&self, stats: &SectionStats) -> Branch {
+        let value = match self {
+            BranchCondition::Accuracy { .. } => stats.accuracy,
+            BranchCondition::Roll { .. } => stats.rolls as f64,
+            BranchCondition::Score { .. } => stats.score as f64,
+            BranchCondition::Other { .. } => return Branch::Normal,
+        };
+        if value >= self.master() {
+            Branch::Master
+        } else if value >= self.expert() {
+            Branch::Expert
+        } else {
+            Branch::Normal
+        }
+    }
+}
+
+/// Directives of a chart that affect branch decisions.
+#[derive(Default)]
+struct BranchEvents {
+    /// Time of each `#SECTION`, with the block and branch it is written in.
+    sections: Vec<(f64, Option<(usize, Branch)>)>,
+    /// Each `#LEVELHOLD`, with the last block before it and the branch it
+    /// is written in, if any.
+    holds: Vec<(Option<usize>, Option<Branch>)>,
+    /// Condition of each `#BRANCHSTART`.
+    conditions: Vec<Option<BranchCondition>>,
+}
+
+impl Chart {
+    /// Decides the branch taken at each `#BRANCHSTART` block.
+    ///
+    /// As in the game, a branch is decided at the start of the measure
+    /// before its `#BRANCHSTART`, from counters that add up from `#START`
+    /// until a `#SECTION` resets them. Accuracy is averaged over the notes
+    /// of the path taken so far. A `#SECTION` in a branch only counts when
+    /// that branch is taken. Once a `#LEVELHOLD` on the path is reached,
+    /// every later block keeps the current branch.
+    pub fn simulate_branches(&self, profile: &BranchProfile) -> Vec<Branch> {
+        let count = self.branch_section_count();
+        let stats = match profile {
+            BranchProfile::Forced(branch) => return vec![*branch; count],
+            BranchProfile::Sections(stats) => stats,
+        };
+        let events = self.branch_events();
+        // Measures are kept in every parsing mode, even those that only hold
+        // rests, so decisions do not depend on the mode. Charts without
+        // them, as deserialized from older versions, fall back to segments.
+        let measures: Vec<(Option<usize>, Option<&str>, f64)> = if self.measures.is_empty() {
+            self.segments
+                .iter()
+                .map(|s| (s.branch_section, s.branch.as_deref(), s.timestamp))
+                .collect()
+        } else {
+            self.measures
+                .iter()
+                .map(|m| (m.branch_section, m.branch.as_deref(), m.timestamp))
+                .collect()
+        };
+
+        let mut taken: Vec<Branch> = Vec::with_capacity(count);
+        let mut decisions: Vec<f64> = Vec::with_capacity(count);
+        // A hold before the first block keeps the branch every chart starts on
+        let mut held = events
+            .holds
+            .iter()
+            .any(|(block, _)| block.is_none())
+            .then_some(Branch::Normal);
+        for index in 0..count {
+            let start = measures
+                .iter()
+                .filter(|m| m.0 == Some(index))
+                .map(|m| m.2)
+                .fold(f64::INFINITY, f64::min);
+            let decision = measures
+                .iter()
+                .filter(|m| on_path(m.0, m.1, &taken))
+                .map(|m| m.2)
+                .filter(|&t| t < start)
+                .fold(f64::NEG_INFINITY, f64::max);
+            let decision = if decision.is_finite() {
+                decision
+            } else {
+                start
+            };
+            decisions.push(decision);
+
+            if let Some(branch) = held {
+                taken.push(branch);
+                continue;
+            }
+
+            let reset = events
+                .sections
+                .iter()
+                .filter(|(time, owner)| {
+                    *time <= decision
+                        && owner.is_none_or(|(block, branch)| taken.get(block) == Some(&branch))
+                })
+                .map(|(time, _)| *time)
+                .fold(f64::NEG_INFINITY, f64::max);
+
+            // Add up the intervals between decisions since the reset
+            let mut total = SectionStats::default();
+            let (mut weighted, mut weight) = (0.0, 0usize);
+            for (i, &end) in decisions.iter().enumerate() {
+                let begin = if i == 0 {
+                    f64::NEG_INFINITY
+                } else {
+                    decisions[i - 1]
+                };
+                if end <= reset {
+                    continue;
+                }
+                let begin = begin.max(reset);
+                let interval = stats.get(i).copied().unwrap_or_default();
+                let notes = self
+                    .segments
+                    .iter()
+                    .filter(|s| on_path(s.branch_section, s.branch.as_deref(), &taken))
+                    .flat_map(|s| &s.notes)
+                    .filter(|n| n.timestamp >= begin && n.timestamp < end)
+                    .filter(|n| n.note_type.is_hit())
+                    .count();
+                weighted += interval.accuracy * notes as f64;
+                weight += notes;
+                total.rolls += interval.rolls;
+                total.score += interval.score;
+            }
+            total.accuracy = if weight > 0 {
+                weighted / weight as f64
+            } else {
+                stats.get(index).map_or(0.0, |s| s.accuracy)
+            };
+
+            let condition = match events.conditions.get(index) {
+                Some(condition) => condition.clone(),
+                None => self
+                    .segments
+                    .iter()
+                    .find(|s| s.branch_section == Some(index))
+                    .and_then(|s| s.branch_condition.as_deref())
+                    .and_then(|c| c.parse().ok()),
+            };
+            let branch = condition.map_or(Branch::Normal, |c| c.evaluate(&total));
+            if events
+                .holds
+                .iter()
+                .any(|hold| *hold == (Some(index), Some(branch)) || *hold == (Some(index), None))
+            {
+                held = Some(branch);
+            }
+            taken.push(branch);
+        }
+        taken
+    }
+
+    /// Returns the chart as played with `profile`: only the branch taken at
+    /// each block is kept, and nothing is tagged with a branch any more.
+    /// `balloons` keeps the counts of the balloons on that path.
+    ///
+    /// Notes after a block keep their times, which follow the last branch
+    /// written in the block. When the branch taken ends earlier or later,
+    /// they are not moved to meet it, see [`crate::ParserOptions::legacy_delay`].
+    pub fn flatten_branches(&self, profile: &BranchProfile) -> Chart {
+        let taken = self.simulate_branches(profile);
+
+        let mut balloons = self.balloons.iter();
+        let mut kept_balloons = Vec::new();
+        let mut segments = Vec::new();
+        for segment in &self.segments {
+            let keep = on_path(segment.branch_section, segment.branch.as_deref(), &taken);
+            for note in &segment.notes {
+                if note.note_type.is_balloon() {
+                    match balloons.next() {
+                        Some(&count) if keep => kept_balloons.push(count),
+                        _ => {}
+                    }
+                }
+            }
+            if keep {
+                segments.push(Segment {
+                    branch: None,
+                    branch_condition: None,
+                    branch_section: None,
+                    ..segment.clone()
+                });
+            }
+        }
+
+        let measures = self
+            .measures
+            .iter()
+            .filter(|m| on_path(m.branch_section, m.branch.as_deref(), &taken))
+            .map(|measure| Measure {
+                branch: None,
+                branch_section: None,
+                ..measure.clone()
+            })
+            .collect();
+
+        let mut block = None;
+        let mut events = Vec::new();
+        for event in &self.events {
+            match event.directive {
+                Directive::BranchStart(_) => {
+                    block = Some(block.map_or(0, |b| b + 1));
+                    continue;
+                }
+                Directive::BranchEnd
+                | Directive::BranchNormal
+                | Directive::BranchExpert
+                | Directive::BranchMaster => continue,
+                _ => {}
+            }
+            let branch = event.branch.as_deref().and_then(|b| b.parse().ok());
+            let keep = match (branch, block) {
+                (Some(branch), Some(block)) => taken.get(block) == Some(&branch),
+                _ => true,
+            };
+            if keep {
+                events.push(ChartEvent {
+                    branch: None,
+                    ..event.clone()
+                });
+            }
+        }
+
+        Chart {
+            player: self.player,
+            course: self.course.clone(),
+            level: self.level.clone(),
+            balloons: kept_balloons,
+            headers: self.headers.clone(),
+            segments,
+            measures,
+            exams: self.exams.clone(),
+            life: self.life,
+            side: self.side,
+            scroll_mode: self.scroll_mode,
+            unknown_headers: self.unknown_headers.clone(),
+            events,
+            span: self.span,
+        }
+    }
+
+    fn branch_section_count(&self) -> usize {
+        let from_segments = self
+            .segments
+            .iter()
+            .filter_map(|s| s.branch_section)
+            .chain(self.measures.iter().filter_map(|m| m.branch_section))
+            .max()
+            .map_or(0, |max| max + 1);
+        let from_events = self
+            .events
+            .iter()
+            .filter(|e| matches!(e.directive, Directive::BranchStart(_)))
+            .count();
+        from_segments.max(from_events)
+    }
+
+    fn branch_events(&self) -> BranchEvents {
+        let mut events = BranchEvents::default();
+        let mut block = None;
+        for event in &self.events {
+            let branch = event.branch.as_deref().and_then(|b| b.parse().ok());
+            let owner = block.zip(branch);
+            match &event.directive {
+                Directive::BranchStart(condition) => {
+                    block = Some(block.map_or(0, |b: usize| b + 1));
+                    events.conditions.push(condition.parse().ok());
+                }
+                Directive::Section => events.sections.push((event.timestamp, owner)),
+                Directive::LevelHold => events.holds.push((block, branch)),
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+/// Whether a measure of `branch` in the block `section` is played when the
+/// branches in `taken` are taken.
+fn on_path(section: Option<usize>, branch: Option<&str>, taken: &[Branch]) -> bool {
+    match (section, branch) {
+        (Some(block), Some(branch)) => taken.get(block).map(Branch::as_str) == Some(branch),
+        _ => true,
+    }
+}
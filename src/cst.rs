@@ -0,0 +1,445 @@
+use crate::parser::is_directive;
+use crate::{NoteType, ParsedTJA, ParserOptions, ParsingMode, TJAParser, TjaError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A byte range in the source text.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TokenKind {
+    /// Byte order mark at the very start of the file.
+    Bom,
+    Whitespace,
+    /// A `//` comment, including the slashes.
+    Comment,
+    HeaderKey,
+    Colon,
+    HeaderValue,
+    /// A directive name, including the `#`.
+    DirectiveName,
+    DirectiveArgs,
+    /// Note digits, commas and the spaces between them.
+    Notes,
+    /// Content the parser does not interpret.
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LineKind {
+    Blank,
+    Comment,
+    Header,
+    Directive,
+    Notes,
+    Unknown,
+}
+
+/// One source line with its tokens and line terminator.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyntaxLine {
+    pub kind: LineKind,
+    pub tokens: Vec<Token>,
+    /// `"\n"`, `"\r\n"`, or empty for a final line without a terminator.
+    pub ending: String,
+    /// Span of the line, excluding the terminator.
+    pub span: Span,
+}
+
+impl SyntaxLine {
+    fn token(&self, kind: TokenKind) -> Option<&Token> {
+        self.tokens.iter().find(|token| token.kind == kind)
+    }
+
+    pub fn text(&self) -> String {
+        self.tokens
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect()
+    }
+
+    /// Header key as written, for header lines.
+    pub fn key(&self) -> Option<&str> {
+        self.token(TokenKind::HeaderKey).map(|t| t.text.as_str())
+    }
+
+    /// Header value without surrounding whitespace, for header lines.
+    pub fn value(&self) -> Option<&str> {
+        match self.kind {
+            LineKind::Header => Some(
+                self.token(TokenKind::HeaderValue)
+                    .map_or("", |t| t.text.as_str()),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Directive name without the `#`, for directive lines.
+    pub fn directive_name(&self) -> Option<&str> {
+        self.token(TokenKind::DirectiveName)
+            .map(|t| t.text.trim_start_matches('#'))
+    }
+
+    /// Directive arguments without surrounding whitespace, for directive lines.
+    pub fn directive_args(&self) -> Option<&str> {
+        match self.kind {
+            LineKind::Directive => Some(
+                self.token(TokenKind::DirectiveArgs)
+                    .map_or("", |t| t.text.as_str()),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn notes(&self) -> Option<&str> {
+        self.token(TokenKind::Notes).map(|t| t.text.as_str())
+    }
+
+    /// Comment text after the `//`.
+    pub fn comment(&self) -> Option<&str> {
+        self.token(TokenKind::Comment).map(|t| &t.text[2..])
+    }
+}
+
+/// A lossless syntax tree of a TJA file.
+///
+/// Every byte of the source, including comments, whitespace and line
+/// terminators, belongs to exactly one token, so formatting the tree with
+/// `Display` reproduces the input exactly. Lines are classified the same way the
+/// parser reads them: anything between `#START` and `#END` that is not a
+/// directive is notes, and `KEY:VALUE` lines elsewhere are headers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyntaxTree {
+    lines: Vec<SyntaxLine>,
+    /// Whether the letters of the extended notes count as notes.
+    #[serde(default)]
+    extended_notes: bool,
+}
+
+impl SyntaxTree {
+    pub fn parse(source: &str) -> Self {
+        Self::lex(source, false)
+    }
+
+    /// Parses `source`, classifying lines as [`TJAParser`] would with
+    /// `options`. Only [`ParserOptions::extended_notes`] matters here.
+    pub fn parse_with_options(source: &str, options: &ParserOptions) -> Self {
+        Self::lex(source, options.extended_notes)
+    }
+
+    fn lex(source: &str, extended_notes: bool) -> Self {
+        let mut lines = Vec::new();
+        let mut in_chart = false;
+        let mut rest = source;
+        let mut offset = 0;
+        while !rest.is_empty() {
+            let (raw, ending) = match rest.find('\n') {
+                Some(i) if rest[..i].ends_with('\r') => (&rest[..i - 1], "\r\n"),
+                Some(i) => (&rest[..i], "\n"),
+                None => (rest, ""),
+            };
+            let line = lex_line(raw, ending, offset, &mut in_chart, extended_notes);
+            offset += raw.len() + ending.len();
+            rest = &rest[raw.len() + ending.len()..];
+            lines.push(line);
+        }
+        Self {
+            lines,
+            extended_notes,
+        }
+    }
+
+    pub fn lines(&self) -> &[SyntaxLine] {
+        &self.lines
+    }
+
+    /// Header lines as `(line index, line)`, matching keys case-insensitively.
+    pub fn find_headers<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> impl Iterator<Item = (usize, &'a SyntaxLine)> + 'a {
+        self.lines.iter().enumerate().filter(move |(_, line)| {
+            line.key()
+                .is_some_and(|k| k.trim().eq_ignore_ascii_case(key))
+        })
+    }
+
+    /// Directive lines as `(line index, line)`, matching names case-insensitively.
+    pub fn find_directives<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = (usize, &'a SyntaxLine)> + 'a {
+        self.lines.iter().enumerate().filter(move |(_, line)| {
+            line.directive_name()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// Replaces the value of the header on line `index`, keeping its key,
+    /// spacing and comment. Returns `false` if the line is not a header.
+    pub fn set_header_value(&mut self, index: usize, value: &str) -> bool {
+        self.set_payload(index, LineKind::Header, TokenKind::HeaderValue, value)
+    }
+
+    /// Replaces the arguments of the directive on line `index`, keeping its
+    /// name, spacing and comment. Returns `false` if the line is not a
+    /// directive.
+    pub fn set_directive_args(&mut self, index: usize, args: &str) -> bool {
+        self.set_payload(index, LineKind::Directive, TokenKind::DirectiveArgs, args)
+    }
+
+    /// Replaces the text of line `index`, keeping its line terminator.
+    pub fn replace_line(&mut self, index: usize, text: &str) {
+        let mut source = String::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            if i == index {
+                source.push_str(text);
+            } else {
+                source.push_str(&line.text());
+            }
+            source.push_str(&line.ending);
+        }
+        *self = Self::lex(&source, self.extended_notes);
+    }
+
+    /// Inserts a line before line `index`, using the file's line terminator.
+    pub fn insert_line(&mut self, index: usize, text: &str) {
+        let ending = self
+            .lines
+            .iter()
+            .find(|line| !line.ending.is_empty())
+            .map_or("\n", |line| line.ending.as_str())
+            .to_string();
+        let mut source = String::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            if i == index {
+                source.push_str(text);
+                source.push_str(&ending);
+            }
+            source.push_str(&line.text());
+            source.push_str(&line.ending);
+        }
+        if index >= self.lines.len() {
+            if self.lines.last().is_some_and(|line| line.ending.is_empty()) {
+                source.push_str(&ending);
+            }
+            source.push_str(text);
+        }
+        *self = Self::lex(&source, self.extended_notes);
+    }
+
+    pub fn remove_line(&mut self, index: usize) {
+        self.lines.remove(index);
+        *self = Self::lex(&self.to_string(), self.extended_notes);
+    }
+
+    /// Parses the tree into the semantic model.
+    pub fn to_parsed_tja(&self) -> Result<ParsedTJA, TjaError> {
+        let options = ParserOptions {
+            extended_notes: self.extended_notes,
+            ..Default::default()
+        };
+        let mut parser = TJAParser::with_options(ParsingMode::Full, options);
+        parser.parse_str(&self.to_string())?;
+        parser.try_get_parsed_tja()
+    }
+
+    fn set_payload(
+        &mut self,
+        index: usize,
+        line_kind: LineKind,
+        kind: TokenKind,
+        text: &str,
+    ) -> bool {
+        let Some(line) = self.lines.get_mut(index) else {
+            return false;
+        };
+        if line.kind != line_kind {
+            return false;
+        }
+        match line.tokens.iter().position(|token| token.kind == kind) {
+            Some(i) => line.tokens[i].text = text.to_string(),
+            None => {
+                // Payload goes after the key's colon or the directive name
+                let anchor = match kind {
+                    TokenKind::HeaderValue => TokenKind::Colon,
+                    _ => TokenKind::DirectiveName,
+                };
+                let mut at = line
+                    .tokens
+                    .iter()
+                    .position(|token| token.kind == anchor)
+                    .map_or(line.tokens.len(), |i| i + 1);
+                if kind == TokenKind::DirectiveArgs {
+                    line.tokens.insert(at, token(TokenKind::Whitespace, " "));
+                    at += 1;
+                }
+                line.tokens.insert(at, token(kind, text));
+            }
+        }
+        self.reindex();
+        true
+    }
+
+    /// Recomputes spans after tokens have been edited in place.
+    fn reindex(&mut self) {
+        let mut offset = 0;
+        for line in &mut self.lines {
+            let start = offset;
+            for token in &mut line.tokens {
+                token.span = Span::new(offset, offset + token.text.len());
+                offset = token.span.end;
+            }
+            line.span = Span::new(start, offset);
+            offset += line.ending.len();
+        }
+    }
+}
+
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            for token in &line.tokens {
+                f.write_str(&token.text)?;
+            }
+            f.write_str(&line.ending)?;
+        }
+        Ok(())
+    }
+}
+
+fn token(kind: TokenKind, text: &str) -> Token {
+    Token {
+        kind,
+        text: text.to_string(),
+        span: Span::default(),
+    }
+}
+
+/// Splits `text` into leading whitespace, content and trailing whitespace.
+fn split_whitespace(text: &str) -> (&str, &str, &str) {
+    let content = text.trim();
+    let lead = text.len() - text.trim_start().len();
+    (&text[..lead], content, &text[lead + content.len()..])
+}
+
+fn lex_line(
+    raw: &str,
+    ending: &str,
+    start: usize,
+    in_chart: &mut bool,
+    extended_notes: bool,
+) -> SyntaxLine {
+    let mut tokens = Vec::new();
+    let mut push = |kind: TokenKind, text: &str| {
+        if !text.is_empty() {
+            tokens.push(token(kind, text));
+        }
+    };
+
+    let (body, bom) = match raw.strip_prefix('\u{feff}') {
+        Some(body) if start == 0 => (body, "\u{feff}"),
+        _ => (raw, ""),
+    };
+    push(TokenKind::Bom, bom);
+
+    let (code, comment) = match body.find("//") {
+        Some(pos) => (&body[..pos], &body[pos..]),
+        None => (body, ""),
+    };
+    let (lead, content, trail) = split_whitespace(code);
+    push(TokenKind::Whitespace, lead);
+
+    let kind = if content.is_empty() {
+        if comment.is_empty() {
+            LineKind::Blank
+        } else {
+            LineKind::Comment
+        }
+    } else if let Some(command) = content.strip_prefix('#') {
+        let name_len = command.find(char::is_whitespace).unwrap_or(command.len());
+        let (name, args) = content.split_at(name_len + 1);
+        let (gap, args, _) = split_whitespace(args);
+        push(TokenKind::DirectiveName, name);
+        push(TokenKind::Whitespace, gap);
+        push(TokenKind::DirectiveArgs, args);
+        if is_directive(content, "START") {
+            *in_chart = true;
+        } else if is_directive(content, "END") {
+            *in_chart = false;
+        }
+        LineKind::Directive
+    } else if *in_chart {
+        let valid = content.bytes().all(|b| {
+            b.is_ascii_digit()
+                || b == b','
+                || b.is_ascii_whitespace()
+                || (extended_notes && NoteType::from_extended_byte(b).is_some())
+        });
+        if valid {
+            push(TokenKind::Notes, content);
+            LineKind::Notes
+        } else {
+            push(TokenKind::Unknown, content);
+            LineKind::Unknown
+        }
+    } else if let Some((key, value)) = content.split_once(':') {
+        let (_, key_text, key_trail) = split_whitespace(key);
+        let (value_lead, value_text, _) = split_whitespace(value);
+        push(TokenKind::HeaderKey, key_text);
+        push(TokenKind::Whitespace, key_trail);
+        push(TokenKind::Colon, ":");
+        push(TokenKind::Whitespace, value_lead);
+        push(TokenKind::HeaderValue, value_text);
+        if key_text.is_empty() {
+            LineKind::Unknown
+        } else {
+            LineKind::Header
+        }
+    } else {
+        push(TokenKind::Unknown, content);
+        LineKind::Unknown
+    };
+
+    push(TokenKind::Whitespace, trail);
+    push(TokenKind::Comment, comment);
+
+    let mut offset = start;
+    for token in &mut tokens {
+        token.span = Span::new(offset, offset + token.text.len());
+        offset = token.span.end;
+    }
+    SyntaxLine {
+        kind,
+        tokens,
+        ending: ending.to_string(),
+        span: Span::new(start, offset),
+    }
+}
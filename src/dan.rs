@@ -0,0 +1,318 @@
+use crate::{Chart, Course, Directive, Segment, Subtitle};
+use serde::{Deserialize, Serialize};
+
+/// Highest `EXAMn` header read.
+pub const MAX_EXAMS: usize = 9;
+
+/// A song of a dan course, from `#NEXTSONG title,subtitle,genre,wave,
+/// scoreinit,scorediff,level,course`. Only the title is required.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NextSong {
+    pub title: String,
+    pub subtitle: Option<Subtitle>,
+    pub genre: Option<String>,
+    /// Audio file, relative to the TJA file.
+    pub wave: Option<String>,
+    pub scoreinit: Option<i32>,
+    pub scorediff: Option<i32>,
+    pub level: Option<i32>,
+    pub course: Option<Course>,
+}
+
+impl std::str::FromStr for NextSong {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(',').map(str::trim);
+        let title = fields.next().filter(|t| !t.is_empty());
+        let Some(title) = title else {
+            return Err(format!("Invalid next song: {}", s));
+        };
+        let mut field = || fields.next().filter(|f| !f.is_empty());
+        Ok(NextSong {
+            title: title.to_string(),
+            subtitle: field().and_then(|f| f.parse().ok()),
+            genre: field().map(String::from),
+            wave: field().map(String::from),
+            scoreinit: field().and_then(|f| f.parse().ok()),
+            scorediff: field().and_then(|f| f.parse().ok()),
+            level: field().and_then(|f| f.parse().ok()),
+            course: field().and_then(|f| f.parse().ok()),
+        })
+    }
+}
+
+/// What an exam measures, from the first field of `EXAMn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExamKind {
+    /// `g`: soul gauge in percent.
+    Gauge,
+    /// `jp`: good judgements.
+    Good,
+    /// `jg`: ok judgements.
+    Ok,
+    /// `jb`: bad judgements.
+    Bad,
+    /// `s`: score.
+    Score,
+    /// `r`: roll hits.
+    Roll,
+    /// `h`: hits, counting good, ok and roll hits.
+    Hit,
+    /// `c`: maximum combo.
+    Combo,
+}
+
+impl std::str::FromStr for ExamKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "g" => Ok(ExamKind::Gauge),
+            "jp" => Ok(ExamKind::Good),
+            "jg" => Ok(ExamKind::Ok),
+            "jb" => Ok(ExamKind::Bad),
+            "s" => Ok(ExamKind::Score),
+            "r" => Ok(ExamKind::Roll),
+            "h" => Ok(ExamKind::Hit),
+            "c" => Ok(ExamKind::Combo),
+            _ => Err(format!("Invalid exam kind: {}", s)),
+        }
+    }
+}
+
+/// How a result is compared with the thresholds, from the last field of
+/// `EXAMn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExamComparison {
+    /// `m`: the result must be at least the threshold.
+    AtLeast,
+    /// `l`: the result must be below the threshold.
+    LessThan,
+}
+
+/// Whether an exam is judged over the whole course or song by song.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ExamScope {
+    Whole,
+    /// Thresholds for each song, in course order.
+    PerSong {
+        red: Vec<f64>,
+        gold: Vec<f64>,
+    },
+}
+
+/// A pass condition of a dan course, from an `EXAMn` header such as
+/// `EXAM1:g,98,100,m`.
+///
+/// Thresholds given as one value per song, separated by `/`
+/// (`EXAM2:jb,10/8/6,5/4/3,l`), make a [`ExamScope::PerSong`] exam; then
+/// `red` and `gold` hold the thresholds of the first song.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exam {
+    pub kind: ExamKind,
+    /// Threshold to pass.
+    pub red: f64,
+    /// Threshold to pass with gold.
+    pub gold: f64,
+    pub comparison: ExamComparison,
+    pub scope: ExamScope,
+}
+
+impl std::str::FromStr for Exam {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid exam: {}", s);
+        let fields: Vec<&str> = s.split(',').map(str::trim).collect();
+        let [kind, red, gold, comparison] = fields[..] else {
+            return Err(invalid());
+        };
+        let thresholds = |field: &str| -> Result<Vec<f64>, String> {
+            field
+                .split('/')
+                .map(|v| v.trim().parse().map_err(|_| invalid()))
+                .collect()
+        };
+        let (red, gold) = (thresholds(red)?, thresholds(gold)?);
+        let comparison = match comparison.to_lowercase().as_str() {
+            "m" => ExamComparison::AtLeast,
+            "l" => ExamComparison::LessThan,
+            _ => return Err(invalid()),
+        };
+        let scope = match (red.len(), gold.len()) {
+            (1, 1) => ExamScope::Whole,
+            (r, g) if r == g => ExamScope::PerSong {
+                red: red.clone(),
+                gold: gold.clone(),
+            },
+            _ => return Err(invalid()),
+        };
+        Ok(Exam {
+            kind: kind.parse()?,
+            red: red[0],
+            gold: gold[0],
+            comparison,
+            scope,
+        })
+    }
+}
+
+/// Results of playing one song of a dan course.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExamStats {
+    /// Soul gauge in percent at the end of the song.
+    pub gauge: f64,
+    pub good: u32,
+    pub ok: u32,
+    pub bad: u32,
+    pub score: u32,
+    pub rolls: u32,
+    pub max_combo: u32,
+}
+
+impl ExamStats {
+    /// The result an exam of `kind` looks at.
+    pub fn value(&self, kind: ExamKind) -> f64 {
+        match kind {
+            ExamKind::Gauge => self.gauge,
+            ExamKind::Good => self.good as f64,
+            ExamKind::Ok => self.ok as f64,
+            ExamKind::Bad => self.bad as f64,
+            ExamKind::Score => self.score as f64,
+            ExamKind::Roll => self.rolls as f64,
+            ExamKind::Hit => (self.good + self.ok + self.rolls) as f64,
+            ExamKind::Combo => self.max_combo as f64,
+        }
+    }
+
+    /// Adds up the songs of a course. The gauge is the one after the last
+    /// song, and the maximum combo is the best of a single song since
+    /// combos across songs are not known.
+    pub fn total(songs: &[ExamStats]) -> ExamStats {
+        songs
+            .iter()
+            .fold(ExamStats::default(), |total, song| ExamStats {
+                gauge: song.gauge,
+                good: total.good + song.good,
+                ok: total.ok + song.ok,
+                bad: total.bad + song.bad,
+                score: total.score + song.score,
+                rolls: total.rolls + song.rolls,
+                max_combo: total.max_combo.max(song.max_combo),
+            })
+    }
+}
+
+/// Outcome of an exam on the result screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ExamOutcome {
+    Failed,
+    /// Red pass.
+    Passed,
+    Gold,
+}
+
+impl Exam {
+    /// Judges the results of each song of the course, in order.
+    pub fn evaluate(&self, songs: &[ExamStats]) -> ExamOutcome {
+        match &self.scope {
+            ExamScope::Whole => {
+                let value = ExamStats::total(songs).value(self.kind);
+                self.judge(value, self.red, self.gold)
+            }
+            ExamScope::PerSong { red, gold } => red
+                .iter()
+                .zip(gold)
+                .enumerate()
+                .map(|(i, (&red, &gold))| {
+                    let value = songs.get(i).copied().unwrap_or_default().value(self.kind);
+                    self.judge(value, red, gold)
+                })
+                .min()
+                .unwrap_or(ExamOutcome::Failed),
+        }
+    }
+
+    fn judge(&self, value: f64, red: f64, gold: f64) -> ExamOutcome {
+        let reaches = |threshold: f64| match self.comparison {
+            ExamComparison::AtLeast => value >= threshold,
+            ExamComparison::LessThan => value < threshold,
+        };
+        if reaches(gold) {
+            ExamOutcome::Gold
+        } else if reaches(red) {
+            ExamOutcome::Passed
+        } else {
+            ExamOutcome::Failed
+        }
+    }
+}
+
+/// One song of a dan chart, see [`Chart::songs`].
+#[derive(Debug, Clone)]
+pub struct ChartSong<'a> {
+    pub song: &'a NextSong,
+    /// Time at which the song starts, from its `#NEXTSONG`.
+    pub start: f64,
+    /// Start of the next song, or the end of the chart.
+    pub end: f64,
+    /// Measures of the song.
+    pub segments: &'a [Segment],
+}
+
+impl Chart {
+    /// Splits a dan chart into the songs started by each `#NEXTSONG`.
+    /// Measures before the first `#NEXTSONG` belong to no song.
+    pub fn songs(&self) -> Vec<ChartSong<'_>> {
+        let starts: Vec<(f64, usize, &NextSong)> = self
+            .events
+            .iter()
+            .filter_map(|event| match &event.directive {
+                Directive::NextSong(song) => Some((event.timestamp, event.measure_index, song)),
+                _ => None,
+            })
+            .collect();
+        let chart_end = self
+            .events
+            .iter()
+            .filter(|event| matches!(event.directive, Directive::End))
+            .map(|event| event.timestamp)
+            .chain(
+                self.segments
+                    .iter()
+                    .flat_map(|s| &s.notes)
+                    .map(|n| n.timestamp),
+            )
+            .fold(f64::NEG_INFINITY, f64::max);
+        // Branches and #DELAY can put segments out of time order, so songs
+        // are split by the measure each #NEXTSONG is written before
+        let first_segment = |measure_index: usize| {
+            self.segments
+                .iter()
+                .position(|s| {
+                    s.notes
+                        .first()
+                        .is_some_and(|n| n.measure_index >= measure_index)
+                })
+                .unwrap_or(self.segments.len())
+        };
+
+        starts
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, measure_index, song))| {
+                let next = starts.get(i + 1);
+                let end = next.map_or(chart_end.max(start), |next| next.0);
+                let first = first_segment(measure_index);
+                let last = next.map_or(self.segments.len(), |next| first_segment(next.1));
+                ChartSong {
+                    song,
+                    start,
+                    end,
+                    segments: &self.segments[first..last.max(first)],
+                }
+            })
+            .collect()
+    }
+}
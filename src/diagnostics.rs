@@ -0,0 +1,86 @@
+use crate::error::Location;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Severity {
+    /// Input the parser has no use for, such as unrecognised headers, or
+    /// that it reads in a way the author may not expect. Never an error,
+    /// even in strict mode.
+    Info,
+    /// Malformed or misplaced input that was ignored or repaired. An error
+    /// in strict mode.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DiagnosticCode {
+    UnknownDirective,
+    MalformedDirective,
+    DirectiveOutsideChart,
+    UnexpectedEnd,
+    MalformedHeader,
+    UnknownHeader,
+    InvalidHeaderValue,
+    NotesOutsideChart,
+    InvalidNoteCharacter,
+    UnterminatedRoll,
+    MissingEnd,
+    BalloonCountMismatch,
+}
+
+impl DiagnosticCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiagnosticCode::UnknownDirective => "unknown-directive",
+            DiagnosticCode::MalformedDirective => "malformed-directive",
+            DiagnosticCode::DirectiveOutsideChart => "directive-outside-chart",
+            DiagnosticCode::UnexpectedEnd => "unexpected-end",
+            DiagnosticCode::MalformedHeader => "malformed-header",
+            DiagnosticCode::UnknownHeader => "unknown-header",
+            DiagnosticCode::InvalidHeaderValue => "invalid-header-value",
+            DiagnosticCode::NotesOutsideChart => "notes-outside-chart",
+            DiagnosticCode::InvalidNoteCharacter => "invalid-note-character",
+            DiagnosticCode::UnterminatedRoll => "unterminated-roll",
+            DiagnosticCode::MissingEnd => "missing-end",
+            DiagnosticCode::BalloonCountMismatch => "balloon-count-mismatch",
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A non-fatal problem found while parsing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: DiagnosticCode,
+    pub message: String,
+    /// Start of the offending input.
+    pub location: Location,
+    /// Length of the offending input in characters.
+    pub length: usize,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}[{}] {}: {}",
+            self.severity, self.code, self.location, self.message
+        )
+    }
+}
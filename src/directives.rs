@@ -1,4 +1,9 @@
+use crate::dan::NextSong;
+use crate::parser::ParserState;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum DirectiveType {
@@ -6,7 +11,7 @@ pub enum DirectiveType {
     Note,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Directive {
     Start(Option<String>), // Optional P1/P2
     End,
@@ -20,22 +25,115 @@ pub enum Directive {
     BranchEnd,
     Measure(i32, i32), // num/den
     Delay(f64),
+    /// Resets the counters the next branch decision is made from.
     Section,
+    /// Keeps the current branch for the rest of the chart.
+    LevelHold,
+    /// Switches the chart to [`crate::ScrollMode::BmScroll`].
+    BmScroll,
+    /// Switches the chart to [`crate::ScrollMode::HbScroll`].
+    HbScroll,
+    /// Switches the chart back to [`crate::ScrollMode::Normal`].
+    NmScroll,
+    /// `#SCROLL` with an imaginary part, as in `#SCROLL 1+2i`: real and
+    /// imaginary scroll speed. The imaginary part moves notes vertically.
+    ComplexScroll(f64, f64),
+    /// Moves the judgement mark over `duration` seconds by `distance`
+    /// pixels, plus `distance_imag` pixels vertically.
+    JposScroll {
+        duration: f64,
+        distance: f64,
+        distance_imag: f64,
+        /// `1`: to the right, `0`: to the left.
+        to_right: bool,
+    },
+    /// Direction notes come from, from `0` (the right, as usual) to `7`.
+    Direction(i32),
+    /// Starts the next song of a dan course.
+    NextSong(NextSong),
+    /// Following notes appear `appear` seconds before they are hit and
+    /// start moving `moving` seconds before; `0 0` turns this off.
+    Sudden {
+        appear: f64,
+        moving: f64,
+    },
     BranchNormal,
     BranchMaster,
     BranchExpert,
+    /// A directive the parser does not interpret, or whose arguments it
+    /// could not read.
+    Raw {
+        name: String,
+        args: String,
+    },
+    /// An event emitted by a [`DirectiveHandler`].
+    Custom {
+        name: String,
+        data: serde_json::Value,
+    },
+}
+
+/// Handles a directive the built-in parser does not know, or overrides one
+/// it does.
+///
+/// The handler receives everything after the directive name, trimmed, and
+/// the parser state, which it may change. It returns the directive to apply:
+/// a built-in one is applied exactly as if it had been written in the file,
+/// and anything else, typically [`Directive::Custom`], is only recorded in
+/// [`crate::Chart::events`]. Returning `Ok(None)` records nothing, and an
+/// error is reported as a malformed directive.
+///
+/// Closures with the same signature as [`DirectiveHandler::handle`]
+/// implement this trait.
+pub trait DirectiveHandler: Send + Sync {
+    fn handle(&self, args: &str, state: &mut ParserState) -> Result<Option<Directive>, String>;
+}
+
+impl<F> DirectiveHandler for F
+where
+    F: Fn(&str, &mut ParserState) -> Result<Option<Directive>, String> + Send + Sync,
+{
+    fn handle(&self, args: &str, state: &mut ParserState) -> Result<Option<Directive>, String> {
+        self(args, state)
+    }
+}
+
+/// Handlers registered on a parser, by uppercase directive name.
+#[derive(Clone, Default)]
+pub(crate) struct DirectiveRegistry {
+    handlers: HashMap<String, Arc<dyn DirectiveHandler>>,
+}
+
+impl DirectiveRegistry {
+    pub(crate) fn insert(&mut self, name: &str, handler: Arc<dyn DirectiveHandler>) {
+        self.handlers.insert(name.to_ascii_uppercase(), handler);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Arc<dyn DirectiveHandler>> {
+        if self.handlers.is_empty() {
+            return None;
+        }
+        self.handlers.get(&name.to_ascii_uppercase()).cloned()
+    }
+}
+
+impl fmt::Debug for DirectiveRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
 }
 
+/// The directives every TJA simulator understands.
 #[derive(Debug, Clone)]
-pub struct DirectiveHandler;
+pub struct BuiltinDirectives;
 
-impl Default for DirectiveHandler {
+impl Default for BuiltinDirectives {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl DirectiveHandler {
+impl BuiltinDirectives {
     pub fn new() -> Self {
         Self
     }
@@ -61,7 +159,10 @@ impl DirectiveHandler {
         } else if base_directive.eq_ignore_ascii_case("BPMCHANGE") {
             args.parse().ok().map(Directive::BpmChange)
         } else if base_directive.eq_ignore_ascii_case("SCROLL") {
-            args.parse().ok().map(Directive::Scroll)
+            match parse_complex(args)? {
+                (real, 0.0) => Some(Directive::Scroll(real)),
+                (real, imag) => Some(Directive::ComplexScroll(real, imag)),
+            }
         } else if base_directive.eq_ignore_ascii_case("GOGOSTART") {
             Some(Directive::GogoStart)
         } else if base_directive.eq_ignore_ascii_case("GOGOEND") {
@@ -77,8 +178,10 @@ impl DirectiveHandler {
         } else if base_directive.eq_ignore_ascii_case("MEASURE") {
             // Avoid collecting into a Vec by using split_once
             if let Some((num_str, den_str)) = args.split_once('/') {
-                if let (Ok(num), Ok(den)) = (num_str.parse(), den_str.parse()) {
-                    return Some(Directive::Measure(num, den));
+                if let (Ok(num), Ok(den)) = (num_str.parse::<i32>(), den_str.parse::<i32>()) {
+                    if num >= 0 && den > 0 {
+                        return Some(Directive::Measure(num, den));
+                    }
                 }
             }
             None
@@ -86,6 +189,42 @@ impl DirectiveHandler {
             args.parse().ok().map(Directive::Delay)
         } else if base_directive.eq_ignore_ascii_case("SECTION") {
             Some(Directive::Section)
+        } else if base_directive.eq_ignore_ascii_case("LEVELHOLD") {
+            Some(Directive::LevelHold)
+        } else if base_directive.eq_ignore_ascii_case("BMSCROLL") {
+            Some(Directive::BmScroll)
+        } else if base_directive.eq_ignore_ascii_case("HBSCROLL") {
+            Some(Directive::HbScroll)
+        } else if base_directive.eq_ignore_ascii_case("NEXTSONG") {
+            args.parse().ok().map(Directive::NextSong)
+        } else if base_directive.eq_ignore_ascii_case("NMSCROLL") {
+            Some(Directive::NmScroll)
+        } else if base_directive.eq_ignore_ascii_case("JPOSSCROLL") {
+            let mut parts = args.split_whitespace();
+            let duration = parts.next()?.parse().ok()?;
+            let (distance, distance_imag) = parse_complex(parts.next()?)?;
+            let to_right = match parts.next()? {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            };
+            Some(Directive::JposScroll {
+                duration,
+                distance,
+                distance_imag,
+                to_right,
+            })
+        } else if base_directive.eq_ignore_ascii_case("DIRECTION") {
+            args.parse()
+                .ok()
+                .filter(|d| (0..8).contains(d))
+                .map(Directive::Direction)
+        } else if base_directive.eq_ignore_ascii_case("SUDDEN") {
+            let (appear, moving) = args.split_once(char::is_whitespace)?;
+            Some(Directive::Sudden {
+                appear: appear.parse().ok()?,
+                moving: moving.trim().parse().ok()?,
+            })
         } else if base_directive.eq_ignore_ascii_case("N") {
             Some(Directive::BranchNormal)
         } else if base_directive.eq_ignore_ascii_case("M") {
@@ -103,7 +242,7 @@ impl DirectiveHandler {
             Some(idx) => &command[..idx],
             None => command,
         };
-        
+
         // Use case-insensitive comparison without allocating
         if base_directive.eq_ignore_ascii_case("START")
             || base_directive.eq_ignore_ascii_case("END")
@@ -113,6 +252,11 @@ impl DirectiveHandler {
             || base_directive.eq_ignore_ascii_case("BRANCHSTART")
             || base_directive.eq_ignore_ascii_case("BRANCHEND")
             || base_directive.eq_ignore_ascii_case("SECTION")
+            || base_directive.eq_ignore_ascii_case("LEVELHOLD")
+            || base_directive.eq_ignore_ascii_case("BMSCROLL")
+            || base_directive.eq_ignore_ascii_case("HBSCROLL")
+            || base_directive.eq_ignore_ascii_case("NMSCROLL")
+            || base_directive.eq_ignore_ascii_case("NEXTSONG")
             || base_directive.eq_ignore_ascii_case("N")
             || base_directive.eq_ignore_ascii_case("M")
             || base_directive.eq_ignore_ascii_case("E")
@@ -123,6 +267,9 @@ impl DirectiveHandler {
             || base_directive.eq_ignore_ascii_case("BPMCHANGE")
             || base_directive.eq_ignore_ascii_case("GOGOSTART")
             || base_directive.eq_ignore_ascii_case("GOGOEND")
+            || base_directive.eq_ignore_ascii_case("JPOSSCROLL")
+            || base_directive.eq_ignore_ascii_case("DIRECTION")
+            || base_directive.eq_ignore_ascii_case("SUDDEN")
         {
             Some(DirectiveType::Note)
         } else {
@@ -130,3 +277,28 @@ impl DirectiveHandler {
         }
     }
 }
+
+/// Parses a real or complex number such as `1.5`, `2i` or `1-0.5i` into its
+/// real and imaginary parts.
+fn parse_complex(s: &str) -> Option<(f64, f64)> {
+    let s = s.trim();
+    let Some(imag) = s.strip_suffix('i') else {
+        return Some((s.parse().ok()?, 0.0));
+    };
+    // The sign between the parts, skipping a leading sign and exponents
+    let split = imag
+        .char_indices()
+        .rev()
+        .find(|&(i, c)| (c == '+' || c == '-') && i > 0 && !imag[..i].ends_with(['e', 'E']))
+        .map(|(i, _)| i);
+    let (real, imag) = match split {
+        Some(i) => (imag[..i].parse().ok()?, &imag[i..]),
+        None => (0.0, imag),
+    };
+    let imag = match imag {
+        "" | "+" => 1.0,
+        "-" => -1.0,
+        imag => imag.parse().ok()?,
+    };
+    Some((real, imag))
+}
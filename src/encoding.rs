@@ -0,0 +1,73 @@
+use encoding_rs::{Encoding, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Text encodings TJA files are commonly stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TextEncoding {
+    Utf8,
+    /// UTF-8 with a leading byte order mark.
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// Shift_JIS as written by Windows tools (CP932).
+    ShiftJis,
+}
+
+impl TextEncoding {
+    fn encoding(&self) -> &'static Encoding {
+        match self {
+            TextEncoding::Utf8 | TextEncoding::Utf8Bom => UTF_8,
+            TextEncoding::Utf16Le => UTF_16LE,
+            TextEncoding::Utf16Be => UTF_16BE,
+            TextEncoding::ShiftJis => SHIFT_JIS,
+        }
+    }
+
+    /// Guesses the encoding of `bytes`.
+    ///
+    /// A byte order mark always wins. Without one, input that is valid UTF-8
+    /// is taken as UTF-8, and anything else is assumed to be Shift_JIS, which
+    /// is what older simulators write.
+    pub fn detect(bytes: &[u8]) -> Self {
+        match Encoding::for_bom(bytes) {
+            Some((encoding, _)) if encoding == UTF_8 => TextEncoding::Utf8Bom,
+            Some((encoding, _)) if encoding == UTF_16LE => TextEncoding::Utf16Le,
+            Some((encoding, _)) if encoding == UTF_16BE => TextEncoding::Utf16Be,
+            _ if std::str::from_utf8(bytes).is_ok() => TextEncoding::Utf8,
+            _ => TextEncoding::ShiftJis,
+        }
+    }
+
+    /// Decodes `bytes`, dropping a byte order mark that matches this
+    /// encoding. Malformed sequences are replaced with U+FFFD.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        self.encoding().decode_with_bom_removal(bytes).0
+    }
+
+    /// Decodes `bytes` like [`TextEncoding::decode`], or returns `None` if
+    /// they contain sequences that are malformed in this encoding.
+    pub fn try_decode<'a>(&self, bytes: &'a [u8]) -> Option<Cow<'a, str>> {
+        match self.encoding().decode_with_bom_removal(bytes) {
+            (text, false) => Some(text),
+            (_, true) => None,
+        }
+    }
+
+    /// Encodes `text`, writing the byte order mark this encoding implies.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf8Bom => [&[0xEF, 0xBB, 0xBF], text.as_bytes()].concat(),
+            TextEncoding::Utf16Le => [0xFF, 0xFE]
+                .into_iter()
+                .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+                .collect(),
+            TextEncoding::Utf16Be => [0xFE, 0xFF]
+                .into_iter()
+                .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+                .collect(),
+            TextEncoding::ShiftJis => SHIFT_JIS.encode(text).0.into_owned(),
+        }
+    }
+}
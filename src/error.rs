@@ -0,0 +1,114 @@
+use crate::diagnostics::Diagnostic;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A 1-based line/column position in the TJA source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn new(line: usize, column: usize) -> Self {
+        Self { line, column }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// An inclusive range between two positions in the TJA source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SourceRange {
+    pub start: Location,
+    pub end: Location,
+}
+
+impl SourceRange {
+    pub fn new(start: Location, end: Location) -> Self {
+        Self { start, end }
+    }
+}
+
+#[derive(Debug)]
+pub enum TjaError {
+    /// Reading the input failed.
+    Io(std::io::Error),
+    /// A `#` command that could not be interpreted.
+    MalformedDirective {
+        location: Location,
+        directive: String,
+    },
+    /// A metadata or course header line that could not be interpreted.
+    MalformedHeader { location: Location, line: String },
+    /// `#END` without a matching `#START`.
+    UnexpectedEnd { location: Location },
+    /// Chart content found before any `#START`.
+    MissingStart { location: Location },
+    /// The parser reached a state it cannot continue from.
+    State { location: Location, message: String },
+    /// A warning-level diagnostic raised in strict mode.
+    Violation(Diagnostic),
+    /// No metadata has been parsed yet.
+    MissingMetadata,
+}
+
+impl TjaError {
+    /// Source position of the offending input, if the error has one.
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            TjaError::MalformedDirective { location, .. }
+            | TjaError::MalformedHeader { location, .. }
+            | TjaError::UnexpectedEnd { location }
+            | TjaError::MissingStart { location }
+            | TjaError::State { location, .. } => Some(*location),
+            TjaError::Violation(diagnostic) => Some(diagnostic.location),
+            TjaError::Io(_) | TjaError::MissingMetadata => None,
+        }
+    }
+}
+
+impl fmt::Display for TjaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TjaError::Io(e) => write!(f, "I/O error: {}", e),
+            TjaError::MalformedDirective {
+                location,
+                directive,
+            } => write!(f, "{}: malformed directive `#{}`", location, directive),
+            TjaError::MalformedHeader { location, line } => {
+                write!(f, "{}: malformed header `{}`", location, line)
+            }
+            TjaError::UnexpectedEnd { location } => {
+                write!(f, "{}: `#END` without matching `#START`", location)
+            }
+            TjaError::MissingStart { location } => {
+                write!(f, "{}: chart data before `#START`", location)
+            }
+            TjaError::State { location, message } => write!(f, "{}: {}", location, message),
+            TjaError::Violation(diagnostic) => {
+                write!(f, "{}: {}", diagnostic.location, diagnostic.message)
+            }
+            TjaError::MissingMetadata => write!(f, "no metadata has been parsed"),
+        }
+    }
+}
+
+impl std::error::Error for TjaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TjaError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for TjaError {
+    fn from(e: std::io::Error) -> Self {
+        TjaError::Io(e)
+    }
+}
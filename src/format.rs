@@ -0,0 +1,251 @@
+use crate::cst::{LineKind, SyntaxLine, SyntaxTree, TokenKind};
+use crate::parser::ParserOptions;
+use crate::writer::{HEADER_ORDER, METADATA_ORDER};
+
+/// How the formatter rewrites the subdivision of each measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoteResolution {
+    /// Keep measures as written.
+    #[default]
+    Preserve,
+    /// Reduce each measure to the fewest characters, e.g. `10101010,` to `1111,`.
+    Minimal,
+    /// Write each measure with this many characters where the notes fit.
+    Fixed(usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    pub resolution: NoteResolution,
+    /// Treat the letters of the extended notes as notes, as with
+    /// [`ParserOptions::extended_notes`]. Otherwise measures using them are
+    /// left as written.
+    pub extended_notes: bool,
+}
+
+/// Rewrites TJA source in the canonical style.
+///
+/// Header keys and directive names are uppercased, headers are ordered
+/// within each block, whitespace is normalized and every measure is put on
+/// a line of its own. Comments are kept. Measures interrupted by a
+/// directive are never resampled, so the chart parses to the same notes.
+pub fn format_tja(source: &str, options: &FormatOptions) -> String {
+    let parser_options = ParserOptions {
+        extended_notes: options.extended_notes,
+        ..Default::default()
+    };
+    let tree = SyntaxTree::parse_with_options(source, &parser_options);
+    let ending = tree
+        .lines()
+        .iter()
+        .find(|line| !line.ending.is_empty())
+        .map_or("\n", |line| line.ending.as_str());
+
+    let mut formatter = Formatter {
+        options,
+        out: Vec::new(),
+        headers: Vec::new(),
+        measure: String::new(),
+        split: false,
+    };
+    for line in tree.lines() {
+        formatter.line(line);
+    }
+    formatter.flush_headers();
+    formatter.flush_piece();
+
+    let mut out = String::new();
+    if tree.lines().first().is_some_and(|line| {
+        line.tokens
+            .first()
+            .is_some_and(|t| t.kind == TokenKind::Bom)
+    }) {
+        out.push('\u{feff}');
+    }
+    // Drop trailing blank lines
+    while formatter.out.last().is_some_and(|line| line.is_empty()) {
+        formatter.out.pop();
+    }
+    for line in formatter.out {
+        out.push_str(&line);
+        out.push_str(ending);
+    }
+    out
+}
+
+struct Formatter<'a> {
+    options: &'a FormatOptions,
+    out: Vec<String>,
+    /// Consecutive header lines, sorted once the run ends.
+    headers: Vec<(Rank, String)>,
+    /// Notes of the current measure that have not been written yet.
+    measure: String,
+    /// Whether part of the current measure was already written.
+    split: bool,
+}
+
+/// Sort key of a header line: the block it belongs to, then its position.
+type Rank = (usize, usize);
+
+impl Formatter<'_> {
+    fn line(&mut self, line: &SyntaxLine) {
+        if line.kind != LineKind::Header {
+            self.flush_headers();
+        }
+        let comment = line
+            .comment()
+            .map(|comment| format!("//{}", comment.trim_end()));
+
+        match line.kind {
+            LineKind::Blank => {
+                self.flush_piece();
+                self.out.push(String::new());
+            }
+            LineKind::Comment => {
+                self.flush_piece();
+                self.out.push(comment.unwrap_or_default());
+            }
+            LineKind::Header => {
+                let key = line.key().unwrap_or_default().to_uppercase();
+                let text = with_comment(
+                    format!("{}:{}", key, line.value().unwrap_or_default()),
+                    comment,
+                );
+                let rank = self.rank(&key);
+                self.headers.push((rank, text));
+            }
+            LineKind::Directive => {
+                self.flush_piece();
+                let name = line.directive_name().unwrap_or_default();
+                let args = line.directive_args().unwrap_or_default();
+                let mut text = format!("#{}", name.to_ascii_uppercase());
+                if !args.is_empty() {
+                    text.push(' ');
+                    text.push_str(args);
+                }
+                self.out.push(with_comment(text, comment));
+            }
+            LineKind::Notes => {
+                let before = self.out.len();
+                for c in line.notes().unwrap_or_default().chars() {
+                    match c {
+                        ',' => self.finish_measure(),
+                        c if c.is_whitespace() => {}
+                        c => self.measure.push(c),
+                    }
+                }
+                if let Some(comment) = comment {
+                    if !self.measure.is_empty() {
+                        self.flush_piece();
+                    }
+                    if self.out.len() > before {
+                        let last = self.out.pop().unwrap_or_default();
+                        self.out.push(with_comment(last, Some(comment)));
+                    } else {
+                        self.out.push(comment);
+                    }
+                }
+            }
+            LineKind::Unknown => {
+                self.flush_piece();
+                let text = line
+                    .tokens
+                    .iter()
+                    .filter(|token| token.kind != TokenKind::Comment)
+                    .map(|token| token.text.as_str())
+                    .collect::<String>();
+                self.out.push(with_comment(
+                    text.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}')
+                        .to_string(),
+                    comment,
+                ));
+            }
+        }
+    }
+
+    /// Orders headers by the conventional key order, without moving a key
+    /// across a boundary between metadata and course headers, which would
+    /// change how the parser reads it. Unknown keys stay after the key they
+    /// follow.
+    fn rank(&self, key: &str) -> Rank {
+        let (block, position) = self.headers.last().map_or((0, 0), |(rank, _)| *rank);
+        let block_of = |position: usize| usize::from(position >= METADATA_ORDER.len());
+        let known = METADATA_ORDER
+            .iter()
+            .chain(HEADER_ORDER)
+            .position(|k| *k == key);
+        match known {
+            Some(known) if self.headers.is_empty() => (0, known),
+            Some(known) if block_of(known) != block_of(position) => (block + 1, known),
+            Some(known) => (block, known),
+            None => (block, position),
+        }
+    }
+
+    fn flush_headers(&mut self) {
+        let mut headers = std::mem::take(&mut self.headers);
+        headers.sort_by_key(|(rank, _)| *rank);
+        self.out.extend(headers.into_iter().map(|(_, text)| text));
+    }
+
+    /// Writes the notes collected so far as an unterminated line.
+    fn flush_piece(&mut self) {
+        if !self.measure.is_empty() {
+            self.out.push(std::mem::take(&mut self.measure));
+            self.split = true;
+        }
+    }
+
+    fn finish_measure(&mut self) {
+        let notes = std::mem::take(&mut self.measure);
+        let notes = if self.split {
+            notes
+        } else {
+            resample(&notes, self.options.resolution)
+        };
+        self.out.push(format!("{},", notes));
+        self.split = false;
+    }
+}
+
+fn with_comment(text: String, comment: Option<String>) -> String {
+    match comment {
+        Some(comment) if text.is_empty() => comment,
+        Some(comment) => format!("{} {}", text, comment),
+        None => text,
+    }
+}
+
+fn resample(notes: &str, resolution: NoteResolution) -> String {
+    let minimal = || {
+        let chars: Vec<char> = notes.chars().collect();
+        let len = chars.len();
+        // Smallest length whose slots hold every note
+        (1..=len)
+            .filter(|n| len.is_multiple_of(*n))
+            .map(|n| len / n)
+            .find(|step| {
+                chars
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &c)| i % step == 0 || c == '0')
+            })
+            .map_or(String::new(), |step| chars.iter().step_by(step).collect())
+    };
+    match resolution {
+        NoteResolution::Preserve => notes.to_string(),
+        NoteResolution::Minimal => minimal(),
+        NoteResolution::Fixed(n) => {
+            let minimal = minimal();
+            let len = minimal.chars().count();
+            if len == 0 || !n.is_multiple_of(len) {
+                return notes.to_string();
+            }
+            let pad = "0".repeat(n / len - 1);
+            minimal
+                .chars()
+                .flat_map(|c| std::iter::once(c).chain(pad.chars()))
+                .collect()
+        }
+    }
+}
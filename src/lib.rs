@@ -1,12 +1,36 @@
+mod branch;
+mod cst;
+mod dan;
+mod diagnostics;
 mod directives;
+mod encoding;
+mod error;
+mod format;
 mod parser;
+mod rational;
+mod scroll;
 mod synthesize;
+mod tempo;
+mod tower;
 mod types;
+mod writer;
 
+pub use branch::*;
+pub use cst::*;
+pub use dan::*;
+pub use diagnostics::*;
 pub use directives::*;
+pub use encoding::*;
+pub use error::*;
+pub use format::*;
 pub use parser::*;
+pub use rational::*;
+pub use scroll::*;
 pub use synthesize::*;
+pub use tempo::*;
+pub use tower::*;
 pub use types::*;
+pub use writer::*;
 
 #[cfg(feature = "python")]
 mod python;
@@ -23,6 +47,38 @@ mod tests {
     use super::*;
     use std::fs;
 
+    const DATA_FILES: [&str; 3] = [
+        "data/SUPERNOVA.tja",
+        "data/mint tears.tja",
+        "data/ニジイロバトン.tja",
+    ];
+
+    fn parse(content: &str) -> ParsedTJA {
+        let mut parser = TJAParser::new();
+        parser.parse_str(content).unwrap();
+        parser.get_parsed_tja()
+    }
+
+    fn branch(name: &str) -> Option<String> {
+        Some(name.to_string())
+    }
+
+    /// Asserts that [`TempoMap`] times the notes of every branch, and those
+    /// outside branches, as the parser did.
+    fn assert_tempo_maps(parsed: &ParsedTJA) {
+        let chart = &parsed.charts[0];
+        for b in [Branch::Normal, Branch::Expert, Branch::Master] {
+            let map = TempoMap::new(chart, &parsed.metadata, b);
+            let segments = chart.segments.iter();
+            for segment in segments.filter(|s| s.branch.as_deref().is_none_or(|s| s == b.as_str()))
+            {
+                for note in &segment.notes {
+                    assert_eq!(map.exact_time(note.beat), note.timestamp);
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_parse_supernova() {
         let content = fs::read_to_string("data/SUPERNOVA.tja").unwrap();
@@ -80,4 +136,1538 @@ mod tests {
             insta::assert_json_snapshot!("supernova_metadata_and_header", parsed_tja);
         });
     }
+
+    #[test]
+    fn test_try_get_parsed_tja() {
+        let parser = TJAParser::new();
+        assert!(matches!(
+            parser.try_get_parsed_tja(),
+            Err(TjaError::MissingMetadata)
+        ));
+
+        let mut parser = TJAParser::new();
+        parser.parse_str("TITLE:Metadata Only\nBPM:150\n").unwrap();
+        let parsed = parser.try_get_parsed_tja().unwrap();
+        assert_eq!(parsed.metadata.get("TITLE").unwrap(), "Metadata Only");
+        assert_eq!(parsed.metadata.bpm, 150.0);
+        assert!(parsed.charts.is_empty());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let content = "TITLE:Diagnostics\nBPM:120\nCOURSE:Oni\nGARBAGE\n\n#START\n  #BPMCHANGE abc\n#FOO 1\n10x1,\n#END\n";
+        let mut parser = TJAParser::new();
+        parser.parse_str(content).unwrap();
+
+        let found: Vec<_> = parser
+            .diagnostics()
+            .iter()
+            .map(|d| (d.code, d.location.line, d.location.column))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (DiagnosticCode::MalformedHeader, 4, 1),
+                (DiagnosticCode::MalformedDirective, 7, 3),
+                (DiagnosticCode::UnknownDirective, 8, 1),
+                (DiagnosticCode::InvalidNoteCharacter, 9, 3),
+            ]
+        );
+
+        let notes = &parser.get_charts()[0].segments[0].notes;
+        assert_eq!(notes.len(), 2);
+
+        // Input strict mode rejects is recorded and skipped otherwise
+        let content = "BPM:120\n#END\nCOURSE:Oni\n\n#START\n1,\n#END\n#END\n1111,\n";
+        let mut parser = TJAParser::new();
+        parser.parse_str(content).unwrap();
+        let found: Vec<_> = parser
+            .diagnostics()
+            .iter()
+            .map(|d| (d.code, d.location.line))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (DiagnosticCode::UnexpectedEnd, 2),
+                (DiagnosticCode::UnexpectedEnd, 8),
+                (DiagnosticCode::NotesOutsideChart, 9),
+            ]
+        );
+        assert_eq!(parser.get_charts().len(), 1);
+
+        // A chart may follow the metadata without any course headers
+        let content = "TITLE:No Course\nBPM:120\n\n#START\n1,\n#END\n";
+        let mut parser = TJAParser::new();
+        parser.parse_str(content).unwrap();
+        assert!(parser.diagnostics().is_empty());
+        let parsed = parser.get_parsed_tja();
+        assert_eq!(parsed.metadata.get("TITLE").unwrap(), "No Course");
+        assert_eq!(parsed.charts.len(), 1);
+        assert_eq!(parsed.charts[0].course, None);
+        assert_eq!(parsed.charts[0].segments[0].notes.len(), 1);
+
+        let mut parser = TJAParser::with_mode(ParsingMode::MetadataOnly);
+        parser.parse_str(content).unwrap();
+        assert!(parser.get_parsed_tja().charts.is_empty());
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let strict = ParserOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let parse = |content: &str| {
+            TJAParser::with_options(ParsingMode::Full, strict.clone()).parse_str(content)
+        };
+
+        let content = fs::read_to_string("data/SUPERNOVA.tja").unwrap();
+        parse(&content).unwrap();
+
+        let err =
+            parse("BPM:120\nCOURSE:Oni\n\n#START\n#BPMCHANGE abc\n1111,\n#END\n").unwrap_err();
+        assert!(matches!(err, TjaError::MalformedDirective { .. }));
+        assert_eq!(err.location(), Some(Location::new(5, 1)));
+
+        let err = parse("BPM:120\nCOURSE:Oni\n\n#START\n#MEASURE 4/0\n1111,\n#END\n").unwrap_err();
+        assert!(matches!(err, TjaError::MalformedDirective { .. }));
+
+        // A tempo must be a positive number
+        let err = parse("BPM:120\nCOURSE:Oni\n\n#START\n#BPMCHANGE 0\n1111,\n#END\n").unwrap_err();
+        assert!(matches!(err, TjaError::MalformedDirective { .. }));
+        for bpm in ["0", "-120"] {
+            let err = parse(&format!("BPM:{}\nCOURSE:Oni\n\n#START\n1,\n#END\n", bpm)).unwrap_err();
+            assert!(matches!(err, TjaError::MalformedHeader { .. }));
+        }
+
+        let err = parse("BPM:120\nCOURSE:Oni\n1111,\n").unwrap_err();
+        assert!(matches!(err, TjaError::MissingStart { .. }));
+
+        let err = parse("BPM:120\n#END\nCOURSE:Oni\n").unwrap_err();
+        assert!(matches!(err, TjaError::UnexpectedEnd { .. }));
+        assert_eq!(err.location(), Some(Location::new(2, 1)));
+
+        let err = parse("BPM:120\nCOURSE:Oni\n\n#START\n1,\n#END\n#END\n").unwrap_err();
+        assert!(matches!(err, TjaError::UnexpectedEnd { .. }));
+
+        let err = parse("BPM:120\nCOURSE:Oni\nGARBAGE\n").unwrap_err();
+        assert!(matches!(err, TjaError::MalformedHeader { .. }));
+
+        let err =
+            parse("BPM:120\nCOURSE:Oni\nBALLOON:5\n\n#START\n7008,\n7008,\n#END\n").unwrap_err();
+        match err {
+            TjaError::Violation(d) => assert_eq!(d.code, DiagnosticCode::BalloonCountMismatch),
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let err = parse("BPM:120\nCOURSE:Oni\n\n#START\n1500,\n#END\n").unwrap_err();
+        match err {
+            TjaError::Violation(d) => {
+                assert_eq!(d.code, DiagnosticCode::UnterminatedRoll);
+                assert_eq!(d.location, Location::new(5, 2));
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let err = parse("BPM:120\nCOURSE:Oni\n\n#START\n1111,\n").unwrap_err();
+        match err {
+            TjaError::Violation(d) => assert_eq!(d.code, DiagnosticCode::MissingEnd),
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // The same inputs are accepted leniently
+        let mut parser = TJAParser::new();
+        parser
+            .parse_str("BPM:120\nCOURSE:Oni\n\n#START\n1500,\n")
+            .unwrap();
+        assert_eq!(parser.diagnostics().len(), 2);
+    }
+
+    #[test]
+    fn test_parse_bytes_encodings() {
+        let content = fs::read_to_string("data/ニジイロバトン.tja").unwrap();
+        let mut parser = TJAParser::new();
+        parser.parse_str(&content).unwrap();
+        let expected = serde_json::to_value(parser.get_parsed_tja()).unwrap();
+
+        let (shift_jis, _, unmappable) = encoding_rs::SHIFT_JIS.encode(&content);
+        assert!(!unmappable);
+        let mut utf16le = vec![0xFF, 0xFE];
+        utf16le.extend(content.encode_utf16().flat_map(u16::to_le_bytes));
+        let mut utf16be = vec![0xFE, 0xFF];
+        utf16be.extend(content.encode_utf16().flat_map(u16::to_be_bytes));
+        let mut utf8_bom = vec![0xEF, 0xBB, 0xBF];
+        utf8_bom.extend(content.as_bytes());
+
+        for (bytes, encoding) in [
+            (content.as_bytes().to_vec(), TextEncoding::Utf8),
+            (utf8_bom, TextEncoding::Utf8Bom),
+            (utf16le, TextEncoding::Utf16Le),
+            (utf16be, TextEncoding::Utf16Be),
+            (shift_jis.into_owned(), TextEncoding::ShiftJis),
+        ] {
+            let mut parser = TJAParser::new();
+            parser.parse_bytes(&bytes).unwrap();
+            assert_eq!(parser.encoding(), Some(encoding));
+            let parsed = serde_json::to_value(parser.get_parsed_tja()).unwrap();
+            assert_eq!(parsed, expected, "{:?}", encoding);
+        }
+
+        // Forcing an encoding skips detection
+        let options = ParserOptions {
+            encoding: Some(TextEncoding::ShiftJis),
+            ..Default::default()
+        };
+        let mut parser = TJAParser::with_options(ParsingMode::Full, options);
+        parser.parse_bytes(content.as_bytes()).unwrap();
+        assert_eq!(parser.encoding(), Some(TextEncoding::ShiftJis));
+        assert_ne!(
+            parser.get_parsed_tja().metadata.get("TITLE"),
+            Some(&"ニジイロバトン".to_string())
+        );
+
+        // Bytes that are not valid in the detected encoding are only
+        // decoded with replacement characters
+        let broken = b"TITLE:\x82\xa0\x82\nBPM:120\n";
+        let encoding = TextEncoding::detect(broken);
+        assert_eq!(encoding, TextEncoding::ShiftJis);
+        assert!(encoding.decode(broken).contains('\u{fffd}'));
+        assert!(encoding.try_decode(broken).is_none());
+        assert_eq!(
+            encoding.try_decode(b"TITLE:\x82\xa0\n").as_deref(),
+            Some("TITLE:あ\n")
+        );
+    }
+
+    /// Asserts that two parses hold the same notes at the same times.
+    fn assert_same_notes(expected: &ParsedTJA, actual: &ParsedTJA, context: &str) {
+        assert_eq!(expected.metadata.raw, actual.metadata.raw, "{}", context);
+        assert_eq!(expected.charts.len(), actual.charts.len(), "{}", context);
+        for (chart, rechart) in expected.charts.iter().zip(&actual.charts) {
+            assert_eq!(chart.headers, rechart.headers, "{}", context);
+            let notes = |chart: &Chart| -> Vec<(Option<String>, NoteType, f64, f64, bool)> {
+                chart
+                    .segments
+                    .iter()
+                    .flat_map(|segment| {
+                        segment.notes.iter().map(|note| {
+                            (
+                                segment.branch.clone(),
+                                note.note_type.clone(),
+                                note.timestamp,
+                                note.scroll,
+                                note.gogo,
+                            )
+                        })
+                    })
+                    .filter(|note| note.1 != NoteType::Empty)
+                    .collect()
+            };
+            let (expected, actual) = (notes(chart), notes(rechart));
+            assert_eq!(expected.len(), actual.len(), "{}", context);
+            for (a, b) in expected.iter().zip(&actual) {
+                assert_eq!(
+                    (&a.0, &a.1, a.3, a.4),
+                    (&b.0, &b.1, b.3, b.4),
+                    "{}",
+                    context
+                );
+                assert!((a.2 - b.2).abs() < 1e-6, "{}: {:?} != {:?}", context, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        for path in DATA_FILES {
+            let parsed = parse(&fs::read_to_string(path).unwrap());
+            let reparsed = parse(&parsed.to_tja_string());
+            assert_same_notes(&parsed, &reparsed, path);
+            assert_same_events(&parsed, &reparsed, path);
+        }
+
+        // Directives the writer cannot derive from the notes come from the
+        // chart's events
+        let content = "TITLE:Dan\nBPM:120\n\nCOURSE:Dan\n#HBSCROLL\n\n#START\n#NEXTSONG Song A,--Sub A,Pop,a.ogg,1000,100,8,Oni\n#SUDDEN 1 0.5\n1100,\n10\n#JPOSSCROLL 0.5 100+20i 1\n#DIRECTION 2\n22,\n,\n#NEXTSONG Song B,,,b.ogg\n#SECTION\n#BRANCHSTART p,50,80\n#N\n1111,\n#E\n#LEVELHOLD\n2222,\n#M\n3333,\n#LYRIC la la\n,\n#BRANCHEND\n#FOO bar baz\n1,\n#END\n";
+        let with_lyrics = |content: &str| {
+            let mut parser = TJAParser::new();
+            parser.add_directive_handler("LYRIC", |args: &str, _: &mut ParserState| {
+                Ok(Some(Directive::Custom {
+                    name: "LYRIC".to_string(),
+                    data: serde_json::Value::String(args.to_string()),
+                }))
+            });
+            parser.parse_str(content).unwrap();
+            parser.get_parsed_tja()
+        };
+        let parsed = with_lyrics(content);
+        let reparsed = with_lyrics(&parsed.to_tja_string());
+        assert_same_notes(&parsed, &reparsed, "dan");
+        assert_same_events(&parsed, &reparsed, "dan");
+        assert_eq!(reparsed.charts[0].scroll_mode, ScrollMode::HbScroll);
+        assert_eq!(reparsed.charts[0].songs().len(), 2);
+
+        // Measures of rests keep their directives
+        let content = "BPM:120\n\nCOURSE:Oni\n\n#START\n1,\n#BARLINEOFF\n#MEASURE 3/4\n,\n00\n#BPMCHANGE 240\n#SCROLL 2\n00,\n#GOGOSTART\n,\n#BARLINEON\n#GOGOEND\n1,\n#END\n";
+        let parsed = parse(content);
+        let reparsed = parse(&parsed.to_tja_string());
+        assert_same_notes(&parsed, &reparsed, "rests");
+        assert_same_events(&parsed, &reparsed, "rests");
+
+        // A tempo of 0 stops the clock, with or without measures
+        let mut parsed =
+            parse("BPM:120\n\nCOURSE:Oni\n\n#START\n1,\n#BPMCHANGE 0\n1,\n,\n1,\n#END\n");
+        assert!(parsed.to_tja_string().contains("#BPMCHANGE 0\n1,\n,\n1,\n"));
+        parsed.charts[0].measures.clear();
+        assert!(parsed.to_tja_string().contains("#BPMCHANGE 0\n"));
+    }
+
+    /// Asserts that two parses hold the same directives and measures at the
+    /// same times.
+    fn assert_same_events(expected: &ParsedTJA, actual: &ParsedTJA, context: &str) {
+        for (chart, rechart) in expected.charts.iter().zip(&actual.charts) {
+            let events = |chart: &Chart| -> Vec<(Option<String>, usize, Directive, f64)> {
+                chart
+                    .events
+                    .iter()
+                    .map(|e| {
+                        (
+                            e.branch.clone(),
+                            e.measure_index,
+                            e.directive.clone(),
+                            e.timestamp,
+                        )
+                    })
+                    .collect()
+            };
+            let (expected, actual) = (events(chart), events(rechart));
+            assert_eq!(
+                expected.len(),
+                actual.len(),
+                "{}: {:#?} {:#?}",
+                context,
+                expected,
+                actual
+            );
+            for (a, b) in expected.iter().zip(&actual) {
+                assert_eq!((&a.0, a.1, &a.2), (&b.0, b.1, &b.2), "{}", context);
+                assert!((a.3 - b.3).abs() < 1e-6, "{}: {:?} != {:?}", context, a, b);
+            }
+
+            assert_eq!(chart.measures.len(), rechart.measures.len(), "{}", context);
+            for (a, b) in chart.measures.iter().zip(&rechart.measures) {
+                assert!((a.timestamp - b.timestamp).abs() < 1e-6, "{}", context);
+                let timing = |m: &Measure| Measure {
+                    timestamp: 0.0,
+                    end: 0.0,
+                    ..m.clone()
+                };
+                assert_eq!(timing(a), timing(b), "{}", context);
+            }
+            assert_eq!(
+                chart.barlines().len(),
+                rechart.barlines().len(),
+                "{}",
+                context
+            );
+        }
+    }
+
+    #[test]
+    fn test_syntax_tree_round_trip() {
+        for path in DATA_FILES {
+            let content = fs::read_to_string(path).unwrap();
+            let tree = SyntaxTree::parse(&content);
+            assert_eq!(tree.to_string(), content, "{}", path);
+            assert_eq!(
+                serde_json::to_value(tree.to_parsed_tja().unwrap()).unwrap(),
+                serde_json::to_value(parse(&content)).unwrap(),
+                "{}",
+                path
+            );
+        }
+
+        let content = "\u{feff}TITLE: Song // main title\r\nBPM:120\r\n\r\nCOURSE:Oni\r\nBALLOON:4,  5\r\n#START  \r\n  #BPMCHANGE 150 // faster\r\n1,\t\r\n#END";
+        let tree = SyntaxTree::parse(content);
+        assert_eq!(tree.to_string(), content);
+        let kinds: Vec<_> = tree.lines().iter().map(|line| line.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                LineKind::Header,
+                LineKind::Header,
+                LineKind::Blank,
+                LineKind::Header,
+                LineKind::Header,
+                LineKind::Directive,
+                LineKind::Directive,
+                LineKind::Notes,
+                LineKind::Directive,
+            ]
+        );
+        for line in tree.lines() {
+            for token in &line.tokens {
+                assert_eq!(&content[token.span.start..token.span.end], token.text);
+            }
+        }
+        assert_eq!(tree.lines()[0].value(), Some("Song"));
+        assert_eq!(tree.lines()[6].comment(), Some(" faster"));
+
+        // Charts open and close as the parser reads them
+        let kinds = |content: &str| -> Vec<_> {
+            let tree = SyntaxTree::parse(content);
+            tree.lines().iter().map(|line| line.kind).collect()
+        };
+        assert_eq!(kinds("#start p1\n1,\n#end\n")[1], LineKind::Notes);
+        assert_eq!(kinds("#STARTED\n1,\n")[1], LineKind::Unknown);
+
+        let mut tree = tree;
+        let (index, _) = tree.find_directives("BPMCHANGE").next().unwrap();
+        assert!(tree.set_directive_args(index, "180"));
+        let (index, _) = tree.find_headers("balloon").next().unwrap();
+        assert!(tree.set_header_value(index, "4,5,6"));
+        assert_eq!(
+            tree.to_string(),
+            content
+                .replace("#BPMCHANGE 150", "#BPMCHANGE 180")
+                .replace("4,  5", "4,5,6")
+        );
+        let edited = tree.lines()[6].tokens.last().unwrap().span;
+        assert_eq!(&tree.to_string()[edited.start..edited.end], "// faster");
+
+        let parsed = tree.to_parsed_tja().unwrap();
+        assert_eq!(parsed.charts[0].headers["BALLOON"], "4,5,6");
+        assert_eq!(parsed.charts[0].segments[0].notes[0].bpm, 180.0);
+    }
+
+    #[test]
+    fn test_format_tja() {
+        for path in DATA_FILES {
+            let content = fs::read_to_string(path).unwrap();
+            let parsed = parse(&content);
+
+            for resolution in [
+                NoteResolution::Preserve,
+                NoteResolution::Minimal,
+                NoteResolution::Fixed(48),
+            ] {
+                let options = FormatOptions {
+                    resolution,
+                    ..Default::default()
+                };
+                let formatted = format_tja(&content, &options);
+                assert_eq!(format_tja(&formatted, &options), formatted, "{}", path);
+
+                assert_same_notes(&parsed, &parse(&formatted), path);
+            }
+        }
+
+        let content = "bpm :150  \ntitle:Song\n\ncourse:Oni // hardest\n#start\n  #bpmchange 200\n1010 1010,2,\n10 // half\n#gogostart\n10,\n#end\n\n\n";
+        let options = FormatOptions {
+            resolution: NoteResolution::Minimal,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_tja(content, &options),
+            "TITLE:Song\nBPM:150\n\nCOURSE:Oni // hardest\n#START\n#BPMCHANGE 200\n1111,\n2,\n10 // half\n#GOGOSTART\n10,\n#END\n"
+        );
+
+        // `#start` opens a chart like `#START`, before and after formatting
+        let parsed = parse(content);
+        assert_eq!(parsed.charts.len(), 1);
+        assert_same_notes(&parsed, &parse(&format_tja(content, &options)), "#start");
+    }
+
+    #[test]
+    fn test_source_spans() {
+        let options = ParserOptions {
+            source_spans: true,
+            ..Default::default()
+        };
+
+        let content = fs::read_to_string("data/SUPERNOVA.tja").unwrap();
+        let lines: Vec<Vec<char>> = content.lines().map(|l| l.chars().collect()).collect();
+        let mut parser = TJAParser::with_options(ParsingMode::Full, options.clone());
+        parser.parse_str(&content).unwrap();
+        for chart in parser.get_charts() {
+            let span = chart.span.unwrap();
+            assert!(lines[span.start.line - 1].starts_with(&['#', 'S', 'T', 'A', 'R', 'T']));
+            assert_eq!(lines[span.end.line - 1], ['#', 'E', 'N', 'D']);
+            for segment in &chart.segments {
+                let span = segment.span.unwrap();
+                assert_eq!(lines[span.end.line - 1][span.end.column - 1], ',');
+                for note in &segment.notes {
+                    let location = note.location.unwrap();
+                    assert_eq!(
+                        lines[location.line - 1][location.column - 1],
+                        note.note_type.to_char()
+                    );
+                }
+            }
+        }
+
+        let mut parser = TJAParser::with_options(ParsingMode::Full, options);
+        parser
+            .parse_str("BPM:120\n\n#START\n  12\n#SCROLL 2\n  30, // comment\n,\n")
+            .unwrap();
+        let chart = &parser.get_charts()[0];
+        assert_eq!(
+            chart.span,
+            Some(SourceRange::new(Location::new(3, 1), Location::new(7, 1)))
+        );
+        let segment = &chart.segments[0];
+        assert_eq!(
+            segment.span,
+            Some(SourceRange::new(Location::new(4, 3), Location::new(6, 5)))
+        );
+        let locations: Vec<_> = segment.notes.iter().map(|n| n.location.unwrap()).collect();
+        assert_eq!(
+            locations,
+            [
+                Location::new(4, 3),
+                Location::new(4, 4),
+                Location::new(6, 3)
+            ]
+        );
+
+        // Spans are off by default and not serialized
+        let mut parser = TJAParser::new();
+        parser.parse_str(&content).unwrap();
+        let json = serde_json::to_string(&parser.get_parsed_tja()).unwrap();
+        assert!(!json.contains("location") && !json.contains("span"));
+    }
+
+    #[test]
+    fn test_chart_events() {
+        let mut parser = TJAParser::new();
+        parser
+            .parse_str(
+                "BPM:120\n\n#START\n#SECTION\n1010\n#BPMCHANGE 240\n1010,\n#SCROLL 2\n,\n#FOO bar baz\n#GOGOSTART\n#END\n",
+            )
+            .unwrap();
+        let events: Vec<_> = parser.get_charts()[0]
+            .events
+            .iter()
+            .map(|e| {
+                (
+                    e.timestamp,
+                    e.measure_index,
+                    e.position,
+                    e.directive.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (0.0, 0, 0.0, Directive::Start(None)),
+                (0.0, 0, 0.0, Directive::Section),
+                (1.0, 0, 0.5, Directive::BpmChange(240.0)),
+                (1.5, 1, 0.0, Directive::Scroll(2.0)),
+                (
+                    2.5,
+                    2,
+                    0.0,
+                    Directive::Raw {
+                        name: "FOO".to_string(),
+                        args: "bar baz".to_string()
+                    }
+                ),
+                (2.5, 2, 0.0, Directive::GogoStart),
+                (2.5, 2, 0.0, Directive::End),
+            ]
+        );
+
+        // Charts serialized before events and measures were kept still load
+        let mut json = serde_json::to_value(&parser.get_charts()[0]).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        fields.remove("events").unwrap();
+        fields.remove("measures").unwrap();
+        let chart: Chart = serde_json::from_value(json).unwrap();
+        assert!(chart.events.is_empty() && chart.measures.is_empty());
+    }
+
+    #[test]
+    fn test_directive_handler() {
+        let mut parser = TJAParser::new();
+        // A simulator-specific directive recorded as a custom event
+        parser.add_directive_handler("lyric", |args: &str, _: &mut ParserState| {
+            Ok(Some(Directive::Custom {
+                name: "LYRIC".to_string(),
+                data: serde_json::Value::String(args.to_string()),
+            }))
+        });
+        // An alias translated into a built-in directive
+        parser.add_directive_handler("TEMPO", |args: &str, _: &mut ParserState| {
+            args.parse()
+                .map(|bpm| Some(Directive::BpmChange(bpm)))
+                .map_err(|_| format!("invalid tempo `{}`", args))
+        });
+        // Direct access to the parser state
+        parser.add_directive_handler("HALFSPEED", |_: &str, state: &mut ParserState| {
+            state.scroll /= 2.0;
+            Ok(None)
+        });
+        parser
+            .parse_str("BPM:120\n\n#START\n#LYRIC hello world\n#TEMPO 240\n#HALFSPEED\n1,\n#TEMPO x\n#END\n")
+            .unwrap();
+
+        let chart = &parser.get_charts()[0];
+        let note = &chart.segments[0].notes[0];
+        assert_eq!((note.bpm, note.scroll), (240.0, 0.5));
+        let directives: Vec<_> = chart.events.iter().map(|e| e.directive.clone()).collect();
+        assert_eq!(
+            directives,
+            [
+                Directive::Start(None),
+                Directive::Custom {
+                    name: "LYRIC".to_string(),
+                    data: serde_json::Value::String("hello world".to_string()),
+                },
+                Directive::BpmChange(240.0),
+                Directive::End,
+            ]
+        );
+        assert_eq!(parser.diagnostics().len(), 1);
+        assert_eq!(parser.diagnostics()[0].message, "invalid tempo `x`");
+    }
+
+    #[test]
+    fn test_retain_unknown_headers() {
+        let content = "TITLE:Song\nBGOFFSET:5\nBPM:120\n\nCOURSE:Oni\nBALLOONNOR:3\nEXAM1:g,80,100,m\n\n#START\n1,\n#END\n";
+
+        let mut parser = TJAParser::new();
+        parser.parse_str(content).unwrap();
+        let parsed = parser.get_parsed_tja();
+        assert_eq!(parsed.metadata.get("BGOFFSET"), None);
+        assert!(!parsed.charts[0].headers.contains_key("BALLOONNOR"));
+
+        let options = ParserOptions {
+            retain_unknown_headers: true,
+            ..Default::default()
+        };
+        let mut parser = TJAParser::with_options(ParsingMode::Full, options);
+        parser.parse_str(content).unwrap();
+        let parsed = parser.get_parsed_tja();
+        assert_eq!(parsed.metadata.get("BGOFFSET"), Some(&"5".to_string()));
+        assert_eq!(parsed.metadata.bpm, 120.0);
+        assert_eq!(parsed.metadata.unknown_keys, ["BGOFFSET"]);
+        let chart = &parsed.charts[0];
+        assert_eq!(chart.headers["BALLOONNOR"], "3");
+        assert_eq!(chart.headers["EXAM1"], "g,80,100,m");
+        assert_eq!(chart.headers["COURSE"], "Oni");
+        assert_eq!(chart.unknown_headers, ["BALLOONNOR"]);
+    }
+
+    #[test]
+    fn test_typed_metadata() {
+        let mut parser = TJAParser::with_mode(ParsingMode::MetadataOnly);
+        parser
+            .parse_str("TITLE:Song\nTITLEJA:曲\nTITLECN:歌曲\nSUBTITLE:++Artist\nSUBTITLEEN:Artist\nWAVE:audio/song.ogg\nGENRE:ナムコオリジナル\nMAKER:Someone\nSCOREMODE:1\nPREIMAGE:jacket.png\nBGMOVIE:\nSIDE:Ex\nBPM:120\n")
+            .unwrap();
+        let metadata = parser.get_parsed_tja().metadata;
+
+        assert_eq!(metadata.title_for("ja"), Some("曲"));
+        assert_eq!(metadata.title_for("zh"), Some("歌曲"));
+        assert_eq!(metadata.title_for("ko"), Some("Song"));
+        assert_eq!(
+            metadata.subtitle_for("ja"),
+            Some(&Subtitle {
+                text: "Artist".to_string(),
+                visibility: SubtitleVisibility::Visible,
+            })
+        );
+        assert_eq!(
+            metadata.subtitle_for("en").unwrap().visibility,
+            SubtitleVisibility::Unspecified
+        );
+        assert_eq!(
+            metadata.wave_path("songs/song.tja"),
+            Some(std::path::PathBuf::from("songs/audio/song.ogg"))
+        );
+        assert_eq!(metadata.genre.as_deref(), Some("ナムコオリジナル"));
+        assert_eq!(metadata.maker.as_deref(), Some("Someone"));
+        assert_eq!(metadata.scoremode, Some(ScoreMode::Ac8To14));
+        assert_eq!(metadata.preimage.as_deref(), Some("jacket.png"));
+        assert_eq!(metadata.bgmovie, None);
+        assert_eq!(metadata.side, Some(Side::Ex));
+        assert_eq!(metadata.bpm, 120.0);
+    }
+
+    #[test]
+    fn test_notes_after_branch_end() {
+        let content = "BPM:120\n\n#START\n1,\n#BRANCHSTART p,0,0\n#N\n1,\n#E\n2,\n#M\n3,\n#BRANCHEND\n4,\n#END\n";
+        let mut parser = TJAParser::new();
+        parser.parse_str(content).unwrap();
+        assert!(parser.diagnostics().is_empty());
+
+        let segments: Vec<_> = parser.get_charts()[0]
+            .segments
+            .iter()
+            .map(|s| (s.branch.clone(), s.notes[0].note_type.clone(), s.timestamp))
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                (None, NoteType::Don, 0.0),
+                (Some("N".to_string()), NoteType::Don, 2.0),
+                (Some("E".to_string()), NoteType::Ka, 2.0),
+                (Some("M".to_string()), NoteType::DonBig, 2.0),
+                (None, NoteType::KaBig, 4.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_branch_state() {
+        let notes = |parsed: &ParsedTJA| -> Vec<(Option<String>, f64, f64, f64, bool)> {
+            parsed.charts[0]
+                .segments
+                .iter()
+                .flat_map(|s| {
+                    s.notes
+                        .iter()
+                        .map(|n| (s.branch.clone(), n.timestamp, n.bpm, n.scroll, n.gogo))
+                })
+                .collect()
+        };
+
+        // Every branch starts from the state at #BRANCHSTART, and the chart
+        // goes on from the last branch
+        let parsed = parse(
+            "BPM:120\nOFFSET:0\n\n#START\n1,\n#BRANCHSTART p,0,0\n#N\n#BPMCHANGE 60\n#GOGOSTART\n#DELAY 1\n1,\n#E\n1,\n#M\n#SCROLL 2\n1,\n#BRANCHEND\n11,\n#END\n",
+        );
+        assert_eq!(
+            notes(&parsed),
+            [
+                (None, 0.0, 120.0, 1.0, false),
+                (branch("N"), 3.0, 60.0, 1.0, true),
+                (branch("E"), 2.0, 120.0, 1.0, false),
+                (branch("M"), 2.0, 120.0, 2.0, false),
+                (None, 4.0, 120.0, 2.0, false),
+                (None, 5.0, 120.0, 2.0, false),
+            ]
+        );
+        assert_tempo_maps(&parsed);
+        let map = TempoMap::new(&parsed.charts[0], &parsed.metadata, Branch::Normal);
+        assert_eq!(
+            (map.bpm_at(4.5), map.exact_time(Rational::from(8))),
+            (120.0, 4.0)
+        );
+
+        // Here the last branch is #N
+        let parsed = parse(
+            "BPM:120\nOFFSET:0\n\n#START\n1,\n#BRANCHSTART p,0,0\n#M\n1,\n#E\n1,\n#N\n#BPMCHANGE 60\n#DELAY 1\n1,\n#BRANCHEND\n11,\n#END\n",
+        );
+        let after: Vec<_> = notes(&parsed)[4..].iter().map(|n| (n.1, n.2)).collect();
+        assert_eq!(after, [(7.0, 60.0), (9.0, 60.0)]);
+        assert_tempo_maps(&parsed);
+
+        let map = TempoMap::new(&parsed.charts[0], &parsed.metadata, Branch::Expert);
+        assert_eq!((map.bpm_at(2.5), map.bpm_at(8.0)), (120.0, 60.0));
+    }
+
+    #[test]
+    fn test_branch_sections() {
+        let parsed = parse(&fs::read_to_string("data/mint tears.tja").unwrap());
+        assert!(parsed.charts[0].branch_sections().is_empty());
+        let sections = parsed.charts[1].branch_sections();
+        assert_eq!(sections.len(), 4);
+        assert_eq!(
+            sections[0].condition,
+            Some(BranchCondition::Accuracy {
+                expert: 45.0,
+                master: 82.96875,
+            })
+        );
+        assert_eq!(sections[3].raw_condition, "p,45,83");
+        for section in &sections {
+            for branch in [Branch::Normal, Branch::Expert, Branch::Master] {
+                let segments = section.segments(branch);
+                assert!(!segments.is_empty());
+                assert!(segments
+                    .iter()
+                    .all(|s| s.branch.as_deref() == Some(branch.as_str())));
+            }
+            // Every branch starts at the same time
+            assert_eq!(section.normal[0].timestamp, section.master[0].timestamp);
+        }
+
+        // Notes after `#BRANCHEND` belong to no branch
+        let mut parser = TJAParser::new();
+        parser
+            .parse_str("BPM:60\n\n#START\n1,\n#BRANCHSTART r,5,10\n#N\n1,\n#E\n2,\n#M\n3,\n#BRANCHEND\n4,\n#BRANCHSTART s,x,1\n#N\n1,\n#END\n")
+            .unwrap();
+        let chart = &parser.get_parsed_tja().charts[0];
+        let last = &chart.segments[4];
+        assert_eq!(last.notes[0].note_type, NoteType::KaBig);
+        assert_eq!((last.branch.as_deref(), last.branch_section), (None, None));
+        assert_eq!(last.timestamp, 8.0);
+
+        let sections = chart.branch_sections();
+        assert_eq!(
+            sections[0].condition,
+            Some(BranchCondition::Roll {
+                expert: 5.0,
+                master: 10.0,
+            })
+        );
+        assert_eq!(sections[1].condition, None);
+        assert!(parser
+            .diagnostics()
+            .iter()
+            .any(|d| d.code == DiagnosticCode::MalformedDirective));
+        assert_eq!(
+            "s,600000,800000"
+                .parse::<BranchCondition>()
+                .unwrap()
+                .to_string(),
+            "s,600000,800000"
+        );
+    }
+
+    #[test]
+    fn test_branch_simulation() {
+        let parsed = parse(&fs::read_to_string("data/mint tears.tja").unwrap());
+        let chart = &parsed.charts[1];
+
+        let profile = BranchProfile::Sections(vec![
+            SectionStats {
+                accuracy: 60.0,
+                ..Default::default()
+            };
+            4
+        ]);
+        assert_eq!(chart.simulate_branches(&profile), vec![Branch::Expert; 4]);
+
+        let master = chart.flatten_branches(&BranchProfile::Forced(Branch::Master));
+        assert!(master.branch_sections().is_empty());
+        let expected = chart
+            .segments
+            .iter()
+            .filter(|s| s.branch.as_deref().is_none_or(|b| b == "M"))
+            .count();
+        assert_eq!(master.segments.len(), expected);
+        assert!(master.segments.iter().all(|s| s.branch.is_none()));
+        assert!(master.events.iter().all(|e| e.branch.is_none()
+            && !matches!(
+                e.directive,
+                Directive::BranchStart(_) | Directive::BranchMaster
+            )));
+        // Measures follow each other once the other branches are gone
+        assert!(master
+            .segments
+            .windows(2)
+            .all(|w| w[0].timestamp < w[1].timestamp));
+
+        let source = |extra: &str, section: &str| {
+            format!(
+                "BPM:60\nBALLOON:3,4,5\n\n#START\n1111,\n1111,\n#BRANCHSTART r,5,10\n#N\n7008,\n#E\n2,\n#M\n{}7008,\n#BRANCHEND\n{}1,\n1,\n#BRANCHSTART r,5,10\n#N\n1,\n#E\n2,\n#M\n7008,\n#BRANCHEND\n#END\n",
+                extra, section
+            )
+        };
+        let profile = BranchProfile::Sections(vec![
+            SectionStats {
+                rolls: 10,
+                ..Default::default()
+            },
+            SectionStats::default(),
+        ]);
+        let simulate = |source: String| {
+            let chart = parse(&source).charts.remove(0);
+            (
+                chart.simulate_branches(&profile),
+                chart.flatten_branches(&profile),
+            )
+        };
+
+        // Roll hits add up across blocks
+        let (taken, flat) = simulate(source("", ""));
+        assert_eq!(taken, vec![Branch::Master, Branch::Master]);
+        assert_eq!(flat.balloons, vec![4, 5]);
+
+        // `#SECTION` resets them before the second decision
+        let (taken, _) = simulate(source("", "#SECTION\n"));
+        assert_eq!(taken, vec![Branch::Master, Branch::Normal]);
+
+        // `#LEVELHOLD` keeps the master branch
+        let (taken, _) = simulate(source("#LEVELHOLD\n", "#SECTION\n"));
+        assert_eq!(taken, vec![Branch::Master, Branch::Master]);
+
+        // The decision is made in the measure before the block, rests or not
+        let source = "BPM:60\n\n#START\n1111,\n#BRANCHSTART r,5,10\n#N\n1,\n#E\n2,\n#M\n3,\n#BRANCHEND\n1111,\n#SECTION\n0000,\n#BRANCHSTART r,5,10\n#N\n1,\n#E\n2,\n#M\n3,\n#BRANCHEND\n#END\n";
+        for mode in [ParsingMode::Full, ParsingMode::FullWithBlanks] {
+            let mut parser = TJAParser::with_mode(mode.clone());
+            parser.parse_str(source).unwrap();
+            let chart = &parser.get_parsed_tja().charts[0];
+            assert_eq!(
+                chart.simulate_branches(&profile),
+                vec![Branch::Master, Branch::Normal],
+                "{:?}",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn test_section_and_level_hold() {
+        let source = "BPM:60\n\n#START\n1111,\n#SECTION\n1111,\n#BRANCHSTART p,50,80\n#N\n1,\n#E\n2,\n#M\n3,\n#BRANCHEND\n#LEVELHOLD\n1,\n1,\n#BRANCHSTART p,50,80\n#N\n1,\n#E\n2,\n#M\n3,\n#BRANCHEND\n#END\n";
+        let mut parser = TJAParser::new();
+        parser.parse_str(source).unwrap();
+        assert!(parser.diagnostics().is_empty());
+        let chart = parser.get_parsed_tja().charts.remove(0);
+
+        let marks: Vec<_> = chart
+            .events
+            .iter()
+            .filter(|e| matches!(e.directive, Directive::Section | Directive::LevelHold))
+            .map(|e| (e.timestamp, e.measure_index, e.directive.clone()))
+            .collect();
+        assert_eq!(
+            marks,
+            vec![
+                (4.0, 1, Directive::Section),
+                (12.0, 3, Directive::LevelHold),
+            ]
+        );
+
+        let stats = |accuracy| SectionStats {
+            accuracy,
+            ..Default::default()
+        };
+        let profile = BranchProfile::Sections(vec![stats(90.0), stats(0.0)]);
+        assert_eq!(
+            chart.simulate_branches(&profile),
+            vec![Branch::Master, Branch::Master]
+        );
+        let profile = BranchProfile::Sections(vec![stats(60.0), stats(100.0)]);
+        assert_eq!(
+            chart.simulate_branches(&profile),
+            vec![Branch::Expert, Branch::Expert]
+        );
+    }
+
+    #[test]
+    fn test_note_position() {
+        let chart = |mode: &str| {
+            let source = format!(
+                "BPM:60\n\n#START\n{}1111,\n#BPMCHANGE 120\n1111,\n#DELAY 1\n#SCROLL 2\n1,\n#END\n",
+                mode
+            );
+            parse(&source)
+        };
+
+        let parsed = chart("");
+        let normal = &parsed.charts[0];
+        assert_eq!(normal.scroll_mode, ScrollMode::Normal);
+        let note = &normal.segments[1].notes[1];
+        assert_eq!(normal.note_position(&parsed.metadata, note, 0.0), 9.0);
+
+        let parsed = chart("#BMSCROLL\n");
+        let (metadata, bm) = (&parsed.metadata, &parsed.charts[0]);
+        assert_eq!(bm.scroll_mode, ScrollMode::BmScroll);
+        let note = &bm.segments[1].notes[1];
+        // Placed by beat, so the faster tempo does not stretch the distance
+        assert_eq!(bm.note_position(metadata, note, 0.0), 5.0);
+        assert_eq!(bm.note_position(metadata, note, 4.0), 1.0);
+        // The lane stands still during the delay, and `#SCROLL` is ignored
+        let last = &bm.segments[2].notes[0];
+        assert_eq!(last.timestamp, 7.0);
+        assert_eq!(bm.note_position(metadata, last, 6.0), 0.0);
+        assert_eq!(bm.note_position(metadata, last, 6.5), 0.0);
+        assert_eq!(bm.note_position(metadata, last, 5.5), 1.0);
+
+        let parsed = chart("#HBSCROLL\n");
+        let hb = &parsed.charts[0];
+        assert_eq!(hb.scroll_mode, ScrollMode::HbScroll);
+        let last = &hb.segments[2].notes[0];
+        assert_eq!(hb.note_position(&parsed.metadata, last, 5.5), 2.0);
+
+        for mode in ["", "#BMSCROLL\n", "#HBSCROLL\n"] {
+            let parsed = chart(mode);
+            let chart = &parsed.charts[0];
+            let expected: Vec<f64> = chart
+                .segments
+                .iter()
+                .flat_map(|s| &s.notes)
+                .map(|note| chart.note_position(&parsed.metadata, note, 1.5))
+                .collect();
+            let playfield = chart.playfield(&parsed.metadata);
+            assert_eq!(playfield.note_positions(1.5), expected);
+            assert_eq!(chart.note_positions(&parsed.metadata, 1.5), expected);
+        }
+
+        // A tempo change within a measure is placed the same in every mode
+        let source = "BPM:120\n\n#START\n#BMSCROLL\n10\n#BPMCHANGE 240\n01,\n1,\n#END\n";
+        for mode in [ParsingMode::Full, ParsingMode::FullWithBlanks] {
+            let mut parser = TJAParser::with_mode(mode.clone());
+            parser.parse_str(source).unwrap();
+            let parsed = parser.get_parsed_tja();
+            let chart = &parsed.charts[0];
+            let notes = chart.segments.iter().flat_map(|s| &s.notes);
+            let positions: Vec<_> = notes
+                .zip(chart.note_positions(&parsed.metadata, 0.0))
+                .filter(|(note, _)| note.note_type != NoteType::Empty)
+                .map(|(_, position)| position)
+                .collect();
+            assert_eq!(positions, [0.0, 3.0, 4.0], "{:?}", mode);
+        }
+
+        // Among the course headers it applies to every chart of the course
+        let source = "BPM:60\n\nCOURSE:Oni\n#BMSCROLL\n\n#START P1\n1,\n#END\n#START P2\n1,\n#END\n\nCOURSE:Hard\n\n#START\n1,\n#END\n";
+        let strict = ParserOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let mut parser = TJAParser::with_options(ParsingMode::Full, strict);
+        parser.parse_str(source).unwrap();
+        assert!(parser.diagnostics().is_empty());
+        let modes: Vec<_> = parser
+            .get_parsed_tja()
+            .charts
+            .iter()
+            .map(|c| c.scroll_mode)
+            .collect();
+        assert_eq!(
+            modes,
+            [
+                ScrollMode::BmScroll,
+                ScrollMode::BmScroll,
+                ScrollMode::Normal
+            ]
+        );
+    }
+
+    #[test]
+    fn test_visual_directives() {
+        let source = "BPM:120\n\n#START\n#SCROLL 1.5+2i\n1,\n#JPOSSCROLL 0.5 -100-20i 1\n#DIRECTION 5\n#SUDDEN 2 1\n#SCROLL -i\n2,\n#NMSCROLL\n#SCROLL 2\n1,\n#JPOSSCROLL 1 100 2\n#END\n";
+        let mut parser = TJAParser::new();
+        parser.parse_str(source).unwrap();
+        let chart = &parser.get_parsed_tja().charts[0];
+
+        let scrolls: Vec<_> = chart
+            .segments
+            .iter()
+            .flat_map(|s| &s.notes)
+            .map(|n| (n.scroll, n.scroll_imag))
+            .collect();
+        assert_eq!(scrolls, vec![(1.5, 2.0), (0.0, -1.0), (2.0, 0.0)]);
+        assert_eq!(chart.scroll_mode, ScrollMode::Normal);
+
+        let events: Vec<_> = chart
+            .events
+            .iter()
+            .filter(|e| e.measure_index == 1)
+            .map(|e| (e.timestamp, e.directive.clone()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    2.0,
+                    Directive::JposScroll {
+                        duration: 0.5,
+                        distance: -100.0,
+                        distance_imag: -20.0,
+                        to_right: true,
+                    }
+                ),
+                (2.0, Directive::Direction(5)),
+                (
+                    2.0,
+                    Directive::Sudden {
+                        appear: 2.0,
+                        moving: 1.0,
+                    }
+                ),
+                (2.0, Directive::ComplexScroll(0.0, -1.0)),
+            ]
+        );
+
+        // An unknown direction of movement is reported, not guessed
+        let codes: Vec<_> = parser.diagnostics().iter().map(|d| d.code).collect();
+        assert_eq!(codes, vec![DiagnosticCode::MalformedDirective]);
+
+        // Complex scroll survives a round trip through the writer
+        let written = parser.get_parsed_tja().to_tja_string();
+        assert!(written.contains("#SCROLL 1.5+2i"));
+        assert!(written.contains("#SCROLL 0-1i"));
+        let notes: Vec<_> = parse(&written).charts[0]
+            .segments
+            .iter()
+            .flat_map(|s| &s.notes)
+            .map(|n| (n.scroll, n.scroll_imag))
+            .collect();
+        assert_eq!(notes, scrolls);
+    }
+
+    #[test]
+    fn test_extended_notes() {
+        let source = "BPM:60\nBALLOON:4\n\n#START\n1A2B,\nC0F0G0D000008000,\nH008,\n#END\n";
+
+        // Without the option the letters are reported and skipped
+        let mut parser = TJAParser::new();
+        parser.parse_str(source).unwrap();
+        let notes = parser.get_parsed_tja().charts[0].segments[0].notes.clone();
+        assert_eq!(notes.len(), 2);
+        assert!(parser
+            .diagnostics()
+            .iter()
+            .all(|d| d.code == DiagnosticCode::InvalidNoteCharacter));
+
+        let options = ParserOptions {
+            extended_notes: true,
+            ..Default::default()
+        };
+        let mut parser = TJAParser::with_options(ParsingMode::Full, options);
+        parser.parse_str(source).unwrap();
+        assert!(parser.diagnostics().is_empty());
+        let chart = &parser.get_parsed_tja().charts[0];
+        let notes: Vec<_> = chart
+            .segments
+            .iter()
+            .flat_map(|s| &s.notes)
+            .map(|n| (n.note_type.clone(), n.timestamp))
+            .collect();
+        // Every letter takes a slot of its measure
+        assert_eq!(
+            notes,
+            vec![
+                (NoteType::Don, 0.0),
+                (NoteType::DonBoth, 1.0),
+                (NoteType::Ka, 2.0),
+                (NoteType::KaBoth, 3.0),
+                (NoteType::Bomb, 4.0),
+                (NoteType::Adlib, 4.5),
+                (NoteType::Purple, 5.0),
+                (NoteType::FuseRoll, 5.5),
+                (NoteType::EndOf, 7.0),
+                (NoteType::RollBigH, 8.0),
+                (NoteType::EndOf, 11.0),
+            ]
+        );
+        assert!(notes
+            .iter()
+            .all(|(t, _)| NoteType::from_char_extended(t.to_char()) == Some(t.clone())));
+        assert_eq!(NoteType::from_char('A'), None);
+        assert_eq!(NoteType::from_char_extended('E'), None);
+
+        // The syntax tree and the formatter only read the letters as notes
+        // with the option as well
+        let line_kind = |tree: &SyntaxTree| tree.lines()[5].kind;
+        assert_eq!(line_kind(&SyntaxTree::parse(source)), LineKind::Unknown);
+        let tree = SyntaxTree::parse_with_options(
+            source,
+            &ParserOptions {
+                extended_notes: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(line_kind(&tree), LineKind::Notes);
+        let reparsed = tree.to_parsed_tja().unwrap();
+        assert_eq!(reparsed.charts[0].segments[1].notes.len(), 5);
+        let mut options = FormatOptions {
+            resolution: NoteResolution::Minimal,
+            ..Default::default()
+        };
+        assert!(format_tja(source, &options).contains("\nC0F0G0D000008000,\n"));
+        options.extended_notes = true;
+        assert!(format_tja(source, &options).contains("\nCFGD0080,\n"));
+
+        let filtered = filter_notes(chart, None);
+        let kinds: Vec<_> = filtered
+            .iter()
+            .map(|n| match n.note_type {
+                FilteredNoteType::Don => "don",
+                FilteredNoteType::Ka => "ka",
+                FilteredNoteType::DrumRoll { .. } => "roll",
+                FilteredNoteType::Balloon { .. } => "balloon",
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec!["don", "don", "ka", "ka", "don", "ka", "balloon", "roll"]
+        );
+    }
+
+    #[test]
+    fn test_dan_course() {
+        let content = "TITLE:Dan\nBPM:60\n\nCOURSE:Dan\nLEVEL:10\nEXAM1:g,80,100,m\nEXAM2:jb,10/8,5/4,l\n\n#START\n#NEXTSONG First,++Artist,Pop,first.ogg,500,100,8,Oni\n1,\n1,\n#NEXTSONG Second\n2,\n#END\n";
+
+        let parsed = parse(content);
+        let chart = &parsed.charts[0];
+        assert_eq!(chart.course, Some(Course::Dan));
+
+        let songs = chart.songs();
+        assert_eq!(songs.len(), 2);
+        assert_eq!(songs[0].song.title, "First");
+        assert_eq!(songs[0].song.wave.as_deref(), Some("first.ogg"));
+        assert_eq!(songs[0].song.level, Some(8));
+        assert_eq!(songs[0].song.course, Some(Course::Oni));
+        assert_eq!((songs[0].start, songs[0].end), (0.0, 8.0));
+        assert_eq!(songs[0].segments.len(), 2);
+        assert_eq!(songs[1].song.title, "Second");
+        assert_eq!(songs[1].song.genre, None);
+        assert_eq!((songs[1].start, songs[1].end), (8.0, 12.0));
+        assert_eq!(songs[1].segments.len(), 1);
+
+        // A delayed branch ends after the next song starts, yet belongs to
+        // the song it is written in
+        let content = "BPM:60\n\nCOURSE:Dan\n\n#START\n#NEXTSONG First\n1,\n#BRANCHSTART p,0,0\n#N\n1,\n#E\n#DELAY 8\n2,\n#M\n3,\n#BRANCHEND\n#NEXTSONG Second\n4,\n#END\n";
+        let parsed = parse(content);
+        let songs = parsed.charts[0].songs();
+        let notes = |i: usize| -> Vec<_> {
+            songs[i]
+                .segments
+                .iter()
+                .map(|s| s.notes[0].note_type.to_char())
+                .collect()
+        };
+        assert_eq!((notes(0), notes(1)), (vec!['1', '1', '2', '3'], vec!['4']));
+
+        assert_eq!(chart.exams.len(), 2);
+        let gauge = &chart.exams[0];
+        assert_eq!(gauge.kind, ExamKind::Gauge);
+        assert_eq!(gauge.scope, ExamScope::Whole);
+        let bad = &chart.exams[1];
+        assert_eq!(bad.comparison, ExamComparison::LessThan);
+        assert_eq!(
+            bad.scope,
+            ExamScope::PerSong {
+                red: vec![10.0, 8.0],
+                gold: vec![5.0, 4.0]
+            }
+        );
+
+        let stats = |gauge, bad| ExamStats {
+            gauge,
+            bad,
+            ..Default::default()
+        };
+        let results = [stats(60.0, 3), stats(90.0, 6)];
+        assert_eq!(gauge.evaluate(&results), ExamOutcome::Passed);
+        assert_eq!(bad.evaluate(&results), ExamOutcome::Passed);
+        let results = [stats(60.0, 3), stats(100.0, 3)];
+        assert_eq!(gauge.evaluate(&results), ExamOutcome::Gold);
+        assert_eq!(bad.evaluate(&results), ExamOutcome::Gold);
+        let results = [stats(60.0, 11), stats(70.0, 0)];
+        assert_eq!(gauge.evaluate(&results), ExamOutcome::Failed);
+        assert_eq!(bad.evaluate(&results), ExamOutcome::Failed);
+
+        assert!("x,1,2,m".parse::<Exam>().is_err());
+        assert!("g,1/2,3,m".parse::<Exam>().is_err());
+    }
+
+    #[test]
+    fn test_tower_course() {
+        let content = "TITLE:Tower\nBPM:60\nLIFE:5\nSIDE:Ex\n\nCOURSE:Tower\nLEVEL:7\n\n#START\n11,\n0,\n#BARLINEOFF\n2,\n#BARLINEON\n1,\n#END\n";
+
+        let mut parser = TJAParser::new();
+        parser.parse_str(content).unwrap();
+        let parsed = parser.get_parsed_tja();
+        assert_eq!(parsed.metadata.side, Some(Side::Ex));
+        let chart = &parsed.charts[0];
+        assert_eq!(chart.course, Some(Course::Tower));
+        assert_eq!(chart.life, Some(5));
+        assert_eq!(chart.side, Some(Side::Ex));
+        assert_eq!(chart.segments.len(), 3);
+        assert_eq!(chart.measures.len(), 4);
+
+        let floors: Vec<_> = chart
+            .floors()
+            .iter()
+            .map(|f| {
+                (
+                    f.number,
+                    f.start,
+                    f.end,
+                    f.measure_index,
+                    f.measures,
+                    f.notes,
+                )
+            })
+            .collect();
+        assert_eq!(
+            floors,
+            vec![
+                (1, 0.0, 4.0, 0, 1, 2),
+                (2, 4.0, 12.0, 1, 2, 1),
+                (3, 12.0, 16.0, 3, 1, 1)
+            ]
+        );
+
+        parser
+            .parse_str("COURSE:5\nLIFE:3\nSIDE:Normal\n\n#START\n1,\n#END\n")
+            .unwrap();
+        let chart = &parser.get_parsed_tja().charts[1];
+        assert_eq!(chart.life, Some(3));
+        assert_eq!(chart.side, Some(Side::Normal));
+    }
+
+    #[test]
+    fn test_exact_positions() {
+        let content = "BPM:150\n\n#START\n1111111,\n#BPMCHANGE 190\n100000000000,\n#BRANCHSTART p,0,0\n#N\n1000000,\n1,\n#M\n100,\n100100100,\n#BRANCHEND\n#MEASURE 3/8\n01,\n#END\n";
+
+        let parsed = parse(content);
+        let notes: Vec<_> = parsed.charts[0]
+            .segments
+            .iter()
+            .flat_map(|s| &s.notes)
+            .collect();
+
+        let third = &notes[2];
+        assert_eq!(third.measure_index, 0);
+        assert_eq!(third.position, Rational::new(2, 7));
+        assert_eq!(third.beat, Rational::new(8, 7));
+
+        let positions: Vec<_> = notes[7..]
+            .iter()
+            .map(|n| (n.measure_index, n.position.to_string(), n.beat.to_string()))
+            .collect();
+        let expected = [
+            (1, "0", "4"),
+            (2, "0", "8"),
+            (3, "0", "12"),
+            (2, "0", "8"),
+            (3, "0", "12"),
+            (3, "1/3", "40/3"),
+            (3, "2/3", "44/3"),
+            (4, "1/2", "67/4"),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(m, p, b)| (m, p.to_string(), b.to_string()))
+            .collect();
+        assert_eq!(positions, expected);
+
+        // Notes that meet across branches have bit-identical timestamps
+        assert_eq!(notes[9].timestamp, 1.6 + 8.0 * 60.0 / 190.0);
+        assert_eq!(notes[9].timestamp.to_bits(), notes[11].timestamp.to_bits());
+
+        assert_eq!(Rational::new(6, -8), Rational::new(-3, 4));
+        assert_eq!(
+            Rational::new(1, 6) + Rational::new(1, 3),
+            Rational::new(1, 2)
+        );
+        assert_eq!(
+            Rational::new(2, 3) / Rational::new(4, 9),
+            Rational::new(3, 2)
+        );
+        assert!(Rational::new(1, 3) < Rational::new(1, 2));
+        assert_eq!("-3/4".parse::<Rational>(), Ok(Rational::new(-3, 4)));
+        assert!("1/0".parse::<Rational>().is_err());
+
+        // Beats whose denominator outgrows i64 are rounded instead of
+        // overflowing
+        let primes = [3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53];
+        let mut content = String::from("BPM:120\n\n#START\n");
+        for p in primes {
+            content += &format!("#MEASURE 1/{}\n1,\n", p);
+        }
+        content += "1,\n#END\n";
+        let last = parse(&content).charts[0]
+            .segments
+            .iter()
+            .flat_map(|s| &s.notes)
+            .next_back()
+            .unwrap()
+            .clone();
+        let expected: f64 = primes.iter().map(|&p| 4.0 / p as f64).sum();
+        assert!((last.beat.to_f64() - expected).abs() < 1e-12);
+        assert!((last.timestamp - expected * 0.5).abs() < 1e-12);
+        assert_eq!(
+            Rational::new(i64::MAX, 3) * Rational::new(3, 1) + Rational::ONE,
+            Rational::new(i64::MAX, 1)
+        );
+    }
+
+    #[test]
+    fn test_note_slots() {
+        let content = "BPM:120\n\n#START\n1020,\n0000000000100000,\n#END\n";
+        let slots = |mode| {
+            let mut parser = TJAParser::with_mode(mode);
+            parser.parse_str(content).unwrap();
+            parser.get_parsed_tja().charts[0]
+                .segments
+                .iter()
+                .flat_map(|s| &s.notes)
+                .map(|n| {
+                    (
+                        n.note_type.to_char(),
+                        n.measure_index,
+                        n.subdivision,
+                        n.slot,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            slots(ParsingMode::Full),
+            [('1', 0, 4, 0), ('2', 0, 4, 2), ('1', 1, 16, 10)]
+        );
+        let with_blanks = slots(ParsingMode::FullWithBlanks);
+        assert_eq!(with_blanks.len(), 20);
+        assert_eq!(with_blanks[1], ('0', 0, 4, 1));
+        assert_eq!(with_blanks[14], ('1', 1, 16, 10));
+
+        // The writer places parsed notes by slot rather than by time
+        let mut parsed = parse(content);
+        parsed.charts[0].segments[1].notes[0].timestamp += 0.01;
+        let written = parsed.to_tja_string();
+        assert!(written.contains("\n12,\n"));
+        assert!(written.contains("\n00000100,\n"));
+    }
+
+    #[test]
+    fn test_tempo_map() {
+        let content = "BPM:120\nOFFSET:0.5\n\n#START\n1000,\n10\n#BPMCHANGE 240\n00,\n,\n#MEASURE 3/4\n1\n#DELAY 1\n11,\n#END\n";
+
+        let parsed = parse(content);
+        assert_tempo_maps(&parsed);
+        let map = TempoMap::new(&parsed.charts[0], &parsed.metadata, Branch::Normal);
+
+        let segments: Vec<_> = map
+            .segments()
+            .iter()
+            .map(|s| (s.time, s.beat.to_string(), s.bpm))
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                (-0.5, "0".to_string(), 120.0),
+                (2.5, "6".to_string(), 240.0),
+                (4.25, "13".to_string(), 0.0),
+                (5.25, "13".to_string(), 240.0),
+            ]
+        );
+
+        // Known in rests too, which carry no note to read `bpm` from
+        assert_eq!(map.bpm_at(2.75), 240.0);
+        assert_eq!(map.bpm_at(3.5), 240.0);
+        assert_eq!(map.bpm_at(-1.0), 120.0);
+        assert_eq!(map.time_to_beat(1.5), 4.0);
+        assert_eq!(map.time_to_beat(4.75), 13.0);
+        assert_eq!(map.time_to_beat(5.5), 14.0);
+        assert_eq!(map.beat_to_time(14.0), 5.5);
+        assert_eq!(map.beat_to_time(-1.0), -1.0);
+
+        let measure = map.measure_at(2.0).unwrap();
+        assert_eq!((measure.measure_index, measure.timestamp), (1, 1.5));
+        let measure = map.measure_at(3.5).unwrap();
+        assert_eq!((measure.measure_index, measure.timestamp), (2, 3.0));
+        let measure = map.measure_at(4.5).unwrap();
+        assert_eq!((measure.measure_index, measure.measure_num), (3, 3));
+        assert!(map.measure_at(-0.6).is_none());
+        assert!(map.measure_at(6.0).is_none());
+    }
+
+    #[test]
+    fn test_barlines() {
+        let content = "BPM:60\n\n#START\n10\n#BARLINEOFF\n10,\n0000,\n#GOGOSTART\n,\n#BARLINEON\n#BRANCHSTART p,0,0\n#N\n1,\n#M\n0,\n#BRANCHEND\n#END\n";
+
+        for mode in [ParsingMode::Full, ParsingMode::FullWithBlanks] {
+            let mut parser = TJAParser::with_mode(mode);
+            parser.parse_str(content).unwrap();
+            let chart = &parser.get_parsed_tja().charts[0];
+            let barlines: Vec<_> = chart
+                .barlines()
+                .into_iter()
+                .map(|b| (b.timestamp, b.measure_index, b.branch, b.visible, b.gogo))
+                .collect();
+            assert_eq!(
+                barlines,
+                [
+                    (0.0, 0, None, true, false),
+                    (4.0, 1, None, false, false),
+                    (8.0, 2, None, false, true),
+                    (12.0, 3, Some("N".to_string()), true, true),
+                    (12.0, 3, Some("M".to_string()), true, true),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_delay_timing() {
+        let content = "BPM:120\nOFFSET:0\n\n#START\n1000,\n#DELAY 0.5\n1010,\n10\n#DELAY -0.25\n10,\n#BRANCHSTART p,0,0\n#N\n#DELAY 1\n1,\n#E\n1,\n#M\n1,\n#BRANCHEND\n1,\n#END\n";
+        let times = |parsed: &ParsedTJA| -> Vec<(Option<String>, f64)> {
+            parsed.charts[0]
+                .segments
+                .iter()
+                .flat_map(|s| s.notes.iter().map(|n| (s.branch.clone(), n.timestamp)))
+                .filter(|(_, t)| *t != 0.0)
+                .collect()
+        };
+
+        let parsed = parse(content);
+        assert_eq!(
+            times(&parsed),
+            [
+                (None, 2.5),
+                (None, 3.5),
+                (None, 4.5),
+                (None, 5.25),
+                (branch("N"), 7.25),
+                (branch("E"), 6.25),
+                (branch("M"), 6.25),
+                (None, 8.25),
+            ]
+        );
+
+        // Bar lines move with the delay as well
+        let barlines: Vec<_> = parsed.charts[0]
+            .barlines()
+            .iter()
+            .map(|b| (b.branch.clone(), b.timestamp))
+            .collect();
+        assert_eq!(
+            barlines,
+            [
+                (None, 0.0),
+                (None, 2.5),
+                (None, 4.5),
+                (branch("N"), 7.25),
+                (branch("E"), 6.25),
+                (branch("M"), 6.25),
+                (None, 8.25),
+            ]
+        );
+
+        assert_tempo_maps(&parsed);
+        assert_eq!(times(&parse(&parsed.to_tja_string())), times(&parsed));
+
+        // Earlier versions kept the delay of #N for the other branches
+        let options = ParserOptions {
+            legacy_delay: true,
+            ..Default::default()
+        };
+        let mut parser = TJAParser::with_options(ParsingMode::Full, options);
+        parser.parse_str(content).unwrap();
+        let legacy = parser.get_parsed_tja();
+        assert_eq!(
+            times(&legacy)[4..],
+            [
+                (branch("N"), 7.25),
+                (branch("E"), 7.25),
+                (branch("M"), 7.25),
+                (None, 9.25),
+            ]
+        );
+        assert_tempo_maps(&legacy);
+
+        // Taking #N does not change when #M, the last branch, hands over
+        let chart = parsed.charts[0].flatten_branches(&BranchProfile::Forced(Branch::Normal));
+        let flat: Vec<_> = chart
+            .segments
+            .iter()
+            .map(|s| s.notes[0].timestamp)
+            .collect();
+        assert_eq!(flat[flat.len() - 2..], [7.25, 8.25]);
+        let map = TempoMap::new(&chart, &parsed.metadata, Branch::Normal);
+        for note in chart.segments.iter().flat_map(|s| &s.notes) {
+            assert_eq!(map.exact_time(note.beat), note.timestamp);
+        }
+    }
 }
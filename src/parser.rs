@@ -1,7 +1,16 @@
-use crate::directives::{Directive, DirectiveHandler};
+use crate::branch::BranchCondition;
+use crate::dan::{Exam, MAX_EXAMS};
+use crate::diagnostics::{Diagnostic, DiagnosticCode, Severity};
+use crate::directives::{BuiltinDirectives, Directive, DirectiveHandler, DirectiveRegistry};
+use crate::encoding::TextEncoding;
+use crate::error::{Location, SourceRange, TjaError};
+use crate::rational::Rational;
+use crate::scroll::ScrollMode;
 use crate::types::*;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ParsingState {
@@ -18,21 +27,78 @@ pub enum ParsingMode {
     FullWithBlanks,
 }
 
+/// Behaviour switches that are independent of the [`ParsingMode`].
+#[derive(Debug, Clone, Default)]
+pub struct ParserOptions {
+    /// Fail on the first warning-level diagnostic (unknown or malformed
+    /// directives, notes outside a chart, unterminated rolls, missing `#END`,
+    /// too few `BALLOON:` counts, ...) instead of recovering from it.
+    pub strict: bool,
+    /// Decode byte input with this encoding instead of detecting it.
+    pub encoding: Option<TextEncoding>,
+    /// Record where each note, segment and chart came from in the source.
+    pub source_spans: bool,
+    /// Read the note letters `A` to `I` some simulators add, such as
+    /// [`NoteType::DonBoth`], instead of reporting them as invalid.
+    pub extended_notes: bool,
+    /// Keep `KEY:VALUE` lines the parser does not recognise instead of
+    /// dropping them. Unknown keys before the first course header are kept
+    /// as metadata, later ones as course headers, and both are listed in
+    /// [`Metadata::unknown_keys`] and [`Chart::unknown_headers`].
+    pub retain_unknown_headers: bool,
+    /// Let `#DELAY` carry over from one branch of a `#BRANCHSTART` into the
+    /// next, as earlier versions of this crate did. By default every branch
+    /// restarts from the delay at its `#BRANCHSTART`, like the rest of the
+    /// timing.
+    ///
+    /// Either way, the chart goes on after `#BRANCHEND` from the end of the
+    /// last branch written in the block, whichever branch is played: its
+    /// time, tempo and delay, which with this option include the delays of
+    /// every branch of the block.
+    pub legacy_delay: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct ParserState {
+    /// Tempo applied to the following notes.
     pub bpm: f64,
+    /// Scroll speed applied to the following notes.
     pub scroll: f64,
+    /// Imaginary part of a complex `#SCROLL`, moving notes vertically.
+    pub scroll_imag: f64,
+    /// Whether the following notes are in go-go time.
     pub gogo: bool,
+    /// Whether the following measures show a bar line.
     pub barline: bool,
+    /// Time signature of the following measures, as in `#MEASURE num/den`.
     pub measure_num: i32,
     pub measure_den: i32,
+    /// Condition of the current `#BRANCHSTART`.
     pub branch_condition: Option<String>,
+    /// `"N"`, `"E"` or `"M"` inside a branch.
     pub current_branch: Option<String>,
+    /// Index of the current `#BRANCHSTART` block within the chart.
+    pub branch_section: Option<usize>,
+    /// Number of `#BRANCHSTART` blocks seen since `#START`.
+    pub branch_sections: usize,
+    /// Whether notes are currently accepted.
     pub parsing_chart: bool,
+    /// Total `#DELAY` in seconds, added to the following notes, measures
+    /// and directives. Negative delays move them earlier.
     pub delay: f64,
+    /// Start of the current measure in seconds, without delay.
     pub timestamp: f64,
-    pub timestamp_branch_start: f64,
+    /// Index of the current measure since `#START`.
+    pub measure_index: usize,
+    /// Exact beat at the start of the current measure, without delay.
+    pub beat: Rational,
+    /// Tempo of the last timed note, from which later times are derived.
+    pub(crate) tempo: TempoAnchor,
+    /// State every branch of the current `#BRANCHSTART` restarts from.
+    pub(crate) branch_start: BranchStart,
+    /// Notes of the current measure, timed when its comma is reached.
     pub current_segment: Option<Segment>,
+    /// Which part of the file is being read.
     pub parsing_state: ParsingState,
 }
 
@@ -41,16 +107,22 @@ impl ParserState {
         Self {
             bpm,
             scroll: 1.0,
+            scroll_imag: 0.0,
             gogo: false,
             barline: true,
             measure_num: 4,
             measure_den: 4,
             branch_condition: None,
             current_branch: None,
+            branch_section: None,
+            branch_sections: 0,
             parsing_chart: false,
             delay: 0.0,
             timestamp: 0.0,
-            timestamp_branch_start: 0.0,
+            measure_index: 0,
+            beat: Rational::ZERO,
+            tempo: TempoAnchor::new(0.0, bpm),
+            branch_start: BranchStart::default(),
             current_segment: None,
             parsing_state: ParsingState::Metadata,
         }
@@ -59,6 +131,109 @@ impl ParserState {
     pub fn measure(&self) -> f64 {
         self.measure_num as f64 / self.measure_den as f64
     }
+
+    fn save_branch_start(&mut self) {
+        self.branch_start = BranchStart {
+            bpm: self.bpm,
+            scroll: self.scroll,
+            scroll_imag: self.scroll_imag,
+            gogo: self.gogo,
+            barline: self.barline,
+            measure_num: self.measure_num,
+            measure_den: self.measure_den,
+            delay: self.delay,
+            timestamp: self.timestamp,
+            measure_index: self.measure_index,
+            beat: self.beat,
+            tempo: self.tempo,
+        };
+    }
+
+    /// Starts `branch` from the state at `#BRANCHSTART`. With
+    /// `legacy_delay`, the delay of the previous branch is kept.
+    fn restore_branch_start(&mut self, branch: &str, legacy_delay: bool) {
+        let start = &self.branch_start;
+        self.current_branch = Some(branch.to_string());
+        self.bpm = start.bpm;
+        self.scroll = start.scroll;
+        self.scroll_imag = start.scroll_imag;
+        self.gogo = start.gogo;
+        self.barline = start.barline;
+        self.measure_num = start.measure_num;
+        self.measure_den = start.measure_den;
+        if !legacy_delay {
+            self.delay = start.delay;
+        }
+        self.timestamp = start.timestamp;
+        self.measure_index = start.measure_index;
+        self.beat = start.beat;
+        self.tempo = start.tempo;
+    }
+}
+
+/// Everything a branch of a `#BRANCHSTART` block inherits, see
+/// [`ParserState::branch_start`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct BranchStart {
+    bpm: f64,
+    scroll: f64,
+    scroll_imag: f64,
+    gogo: bool,
+    barline: bool,
+    measure_num: i32,
+    measure_den: i32,
+    delay: f64,
+    timestamp: f64,
+    measure_index: usize,
+    beat: Rational,
+    tempo: TempoAnchor,
+}
+
+/// Time and beat at which a tempo took effect. Times of later beats are
+/// derived from it in one step, so that they do not drift the way a sum
+/// of note lengths would.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TempoAnchor {
+    time: f64,
+    beat: Rational,
+    bpm: f64,
+}
+
+impl TempoAnchor {
+    fn new(time: f64, bpm: f64) -> Self {
+        Self {
+            time,
+            beat: Rational::ZERO,
+            bpm,
+        }
+    }
+
+    fn time_at(&self, beat: Rational) -> f64 {
+        self.time + (beat - self.beat).to_f64() * 60.0 / self.bpm
+    }
+
+    /// The tempo from `beat` on, switching to `bpm` if it differs.
+    fn with_bpm(self, beat: Rational, bpm: f64) -> Self {
+        if bpm == self.bpm {
+            self
+        } else {
+            Self {
+                time: self.time_at(beat),
+                beat,
+                bpm,
+            }
+        }
+    }
+}
+
+/// A directive waiting for the end of its measure.
+#[derive(Debug, Clone)]
+struct PendingEvent {
+    /// Number of note characters of the measure before the directive.
+    note_index: usize,
+    delay: f64,
+    branch: Option<String>,
+    directive: Directive,
 }
 
 #[derive(Debug, Clone)]
@@ -73,7 +248,22 @@ pub struct TJAParser {
     header_keys: HashSet<String>,
     inheritable_header_keys: HashSet<String>,
     mode: ParsingMode,
-    directive_handler: DirectiveHandler,
+    builtin_directives: BuiltinDirectives,
+    custom_directives: DirectiveRegistry,
+    options: ParserOptions,
+    encoding: Option<TextEncoding>,
+    diagnostics: Vec<Diagnostic>,
+    /// Directives of the current measure, placed once its length is known.
+    pending_events: Vec<PendingEvent>,
+    open_roll: Option<Location>,
+    chart_start: Location,
+    /// Set by `#BMSCROLL` or `#HBSCROLL` among the course headers, for the
+    /// charts that follow them.
+    header_scroll_mode: ScrollMode,
+    /// Whether a chart ended since the last course header.
+    chart_ended: bool,
+    line: usize,
+    column: usize,
 }
 
 impl Default for TJAParser {
@@ -96,32 +286,40 @@ impl TJAParser {
             "SONGVOL",
             "SEVOL",
             "SCOREMODE",
+            "PREIMAGE",
+            "BGMOVIE",
+            "LYRICS",
+            "SIDE",
+            "LIFE",
         ]
         .into_iter()
         .map(String::from)
         .collect();
 
-        let localized_metadata_keys: HashSet<String> = ["JA", "EN", "CN", "TW", "ZH", "KO"]
-            .into_iter()
+        let localized_metadata_keys: HashSet<String> = TITLE_LOCALES
+            .iter()
             .flat_map(|loc| vec![format!("TITLE{}", loc), format!("SUBTITLE{}", loc)])
             .collect();
 
         metadata_keys.extend(localized_metadata_keys);
 
-        let header_keys: HashSet<String> = vec![
+        let mut header_keys: HashSet<String> = vec![
             "COURSE",
             "LEVEL",
             "BALLOON",
             "SCOREINIT",
             "SCOREDIFF",
             "STYLE",
+            "LIFE",
+            "SIDE",
         ]
         .into_iter()
         .map(String::from)
         .collect();
+        header_keys.extend((1..=MAX_EXAMS).map(|n| format!("EXAM{}", n)));
 
         let inheritable_header_keys: HashSet<String> =
-            ["COURSE", "LEVEL", "SCOREINIT", "SCOREDIFF"]
+            ["COURSE", "LEVEL", "SCOREINIT", "SCOREDIFF", "LIFE", "SIDE"]
                 .into_iter()
                 .map(String::from)
                 .collect();
@@ -137,7 +335,18 @@ impl TJAParser {
             header_keys,
             inheritable_header_keys,
             mode: ParsingMode::Full,
-            directive_handler: DirectiveHandler::new(),
+            builtin_directives: BuiltinDirectives::new(),
+            custom_directives: DirectiveRegistry::default(),
+            options: ParserOptions::default(),
+            encoding: None,
+            diagnostics: Vec::new(),
+            pending_events: Vec::new(),
+            open_roll: None,
+            chart_start: Location::new(0, 0),
+            header_scroll_mode: ScrollMode::Normal,
+            chart_ended: false,
+            line: 0,
+            column: 0,
         }
     }
 
@@ -147,120 +356,412 @@ impl TJAParser {
         parser
     }
 
-    pub fn parse_str(&mut self, content: &str) -> Result<(), String> {
+    pub fn with_options(mode: ParsingMode, options: ParserOptions) -> Self {
+        let mut parser = Self::with_mode(mode);
+        parser.options = options;
+        parser
+    }
+
+    /// Decodes `bytes` (see [`TextEncoding::detect`]) and parses the result.
+    pub fn parse_bytes(&mut self, bytes: &[u8]) -> Result<(), TjaError> {
+        let encoding = self
+            .options
+            .encoding
+            .unwrap_or_else(|| TextEncoding::detect(bytes));
+        self.encoding = Some(encoding);
+        self.parse_str(&encoding.decode(bytes))
+    }
+
+    pub fn parse_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), TjaError> {
+        let bytes = std::fs::read(path)?;
+        self.parse_bytes(&bytes)
+    }
+
+    pub fn parse_str(&mut self, content: &str) -> Result<(), TjaError> {
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
         let mut metadata_dict = HashMap::with_capacity(self.metadata_keys.len());
-        let mut notes_buffer = Vec::new();
 
         self.state = Some(ParserState::new(120.0));
         self.state_internal = Some(ParserState::new(120.0));
+        self.header_scroll_mode = ScrollMode::Normal;
+        self.chart_ended = false;
+
+        for (index, raw_line) in content.lines().enumerate() {
+            if let Some(line) = normalize_line(raw_line) {
+                self.line = index + 1;
+                self.column = column_of(raw_line, line);
 
-        for line in content.lines() {
-            if let Some(line) = normalize_line(line) {
                 match self.state.as_ref().unwrap().parsing_state {
                     ParsingState::Metadata => {
                         if let Some((key, value)) = self.parse_metadata_or_header(line) {
-                            let state = self.state.as_mut().unwrap();
-                            if self.metadata_keys.contains(&key) {
+                            let retained = self.options.retain_unknown_headers
+                                && !self.metadata_keys.contains(&key)
+                                && !self.header_keys.contains(&key);
+                            if retained {
+                                self.report(
+                                    Severity::Info,
+                                    DiagnosticCode::UnknownHeader,
+                                    0,
+                                    line,
+                                    format!("unrecognized metadata `{}` is kept", key),
+                                )?;
+                                metadata_dict.insert(key, value);
+                            } else if self.metadata_keys.contains(&key) {
                                 if key == "BPM" {
                                     if let Ok(bpm) = value.parse::<f64>() {
-                                        state.bpm = bpm;
+                                        if bpm <= 0.0 || !bpm.is_finite() {
+                                            self.report_invalid_value(line, &key, &value)?;
+                                        }
+                                        self.state.as_mut().unwrap().bpm = bpm;
                                         self.state_internal.as_mut().unwrap().bpm = bpm;
+                                    } else {
+                                        self.report_invalid_value(line, &key, &value)?;
                                     }
                                 }
+                                // Song-wide defaults of course headers such as `LIFE`
+                                if self.inheritable_header_keys.contains(&key) {
+                                    self.inherited_headers.insert(key.clone(), value.clone());
+                                }
                                 metadata_dict.insert(key, value);
                             } else {
                                 // Take ownership of metadata_dict to avoid clone
                                 self.metadata =
-                                    Some(Metadata::new(std::mem::take(&mut metadata_dict)));
+                                    Some(self.new_metadata(std::mem::take(&mut metadata_dict)));
 
                                 match self.mode {
                                     ParsingMode::MetadataOnly => return Ok(()),
                                     ParsingMode::MetadataAndHeader
                                     | ParsingMode::Full
                                     | ParsingMode::FullWithBlanks => {
-                                        state.parsing_state = ParsingState::Header;
-                                        self.handle_metadata_or_header(line);
+                                        self.state.as_mut().unwrap().parsing_state =
+                                            ParsingState::Header;
+                                        self.handle_metadata_or_header(line)?;
                                     }
                                 }
                             }
+                        } else if is_directive(line, "START") {
+                            // A chart without any course headers
+                            self.metadata =
+                                Some(self.new_metadata(std::mem::take(&mut metadata_dict)));
+                            if self.mode == ParsingMode::MetadataOnly {
+                                return Ok(());
+                            }
+                            self.state.as_mut().unwrap().parsing_state = ParsingState::Notes;
+                            self.process_directive(&line[1..])?;
+                        } else if !self.read_header_directive(line) {
+                            self.report_unparsed_line(line)?;
                         }
                     }
                     ParsingState::Header => {
                         let state = self.state.as_mut().unwrap();
-                        if line.starts_with("#START") {
+                        if is_directive(line, "START") {
                             state.parsing_state = ParsingState::Notes;
                             self.process_directive(&line[1..])?;
                         } else {
-                            self.handle_metadata_or_header(line);
+                            // The headers of the next course start over
+                            if std::mem::take(&mut self.chart_ended) {
+                                self.header_scroll_mode = ScrollMode::Normal;
+                            }
+                            if !self.read_header_directive(line) {
+                                self.handle_metadata_or_header(line)?;
+                            }
                         }
                     }
                     ParsingState::Notes => {
+                        // A chart reaches up to its last line, even without `#END`
+                        if self.options.source_spans && !is_directive(line, "START") {
+                            let location = self.location(0);
+                            if let Some(span) =
+                                self.charts.last_mut().and_then(|chart| chart.span.as_mut())
+                            {
+                                span.end = location;
+                            }
+                        }
                         if self.mode == ParsingMode::Full
                             || self.mode == ParsingMode::FullWithBlanks
                         {
-                            if line.starts_with("#END") {
-                                if !notes_buffer.is_empty() {
-                                    self.process_notes_buffer(&notes_buffer)?;
-                                    notes_buffer.clear();
-                                }
+                            if is_directive(line, "END") {
                                 self.process_directive(&line[1..])?;
                                 let state = self.state.as_mut().unwrap();
                                 state.parsing_state = ParsingState::Header;
+                                self.chart_ended = true;
                             } else if let Some(directive) = line.strip_prefix('#') {
-                                if !notes_buffer.is_empty() {
-                                    self.process_notes_buffer(&notes_buffer)?;
-                                    notes_buffer.clear();
-                                }
                                 self.process_directive(directive)?;
                             } else {
-                                notes_buffer.push(line.to_string());
+                                self.process_notes(line)?;
                             }
-                        } else if line.starts_with("#END") {
+                        } else if is_directive(line, "END") {
                             let state = self.state.as_mut().unwrap();
                             state.parsing_state = ParsingState::Header;
+                            self.chart_ended = true;
                         }
                     }
                 }
             }
         }
 
-        if !notes_buffer.is_empty() {
-            self.process_notes_buffer(&notes_buffer)?;
+        if self.state.as_ref().unwrap().parsing_state == ParsingState::Notes {
+            self.flush_pending_events();
+            self.report_missing_end()?;
+        }
+
+        // A file may consist of nothing but metadata
+        if self.metadata.is_none() {
+            self.metadata = Some(self.new_metadata(metadata_dict));
         }
 
         Ok(())
     }
 
-    fn process_notes_buffer(&mut self, notes_buffer: &[String]) -> Result<(), String> {
-        for line in notes_buffer {
-            if let Some(command) = line.strip_prefix("#") {
-                self.process_directive(command)?;
+    /// Location of the current line, `offset` characters after its first
+    /// non-whitespace character.
+    fn location(&self, offset: usize) -> Location {
+        Location::new(self.line, self.column + offset)
+    }
+
+    fn state_error(&self, message: &str) -> TjaError {
+        TjaError::State {
+            location: self.location(0),
+            message: message.to_string(),
+        }
+    }
+
+    /// Records a diagnostic for `text`, which starts `offset` characters into
+    /// the current line. In strict mode warnings are returned as errors.
+    fn report(
+        &mut self,
+        severity: Severity,
+        code: DiagnosticCode,
+        offset: usize,
+        text: &str,
+        message: String,
+    ) -> Result<(), TjaError> {
+        self.report_at(severity, code, self.location(offset), text, message)
+    }
+
+    fn report_at(
+        &mut self,
+        severity: Severity,
+        code: DiagnosticCode,
+        location: Location,
+        text: &str,
+        message: String,
+    ) -> Result<(), TjaError> {
+        let diagnostic = Diagnostic {
+            severity,
+            code,
+            message,
+            location,
+            length: text.chars().count(),
+        };
+        self.diagnostics.push(diagnostic.clone());
+
+        if !self.options.strict || severity != Severity::Warning {
+            return Ok(());
+        }
+        Err(match code {
+            DiagnosticCode::MalformedDirective => TjaError::MalformedDirective {
+                location,
+                directive: text.trim_start_matches('#').to_string(),
+            },
+            DiagnosticCode::MalformedHeader | DiagnosticCode::InvalidHeaderValue => {
+                TjaError::MalformedHeader {
+                    location,
+                    line: text.to_string(),
+                }
+            }
+            DiagnosticCode::UnexpectedEnd => TjaError::UnexpectedEnd { location },
+            DiagnosticCode::NotesOutsideChart => TjaError::MissingStart { location },
+            _ => TjaError::Violation(diagnostic),
+        })
+    }
+
+    fn report_invalid_value(&mut self, line: &str, key: &str, value: &str) -> Result<(), TjaError> {
+        self.report(
+            Severity::Warning,
+            DiagnosticCode::InvalidHeaderValue,
+            0,
+            line,
+            format!("invalid value `{}` for `{}`", value, key),
+        )
+    }
+
+    /// Reads the scroll mode directives that may be written among the course
+    /// headers instead of inside the chart. Returns `false` for any other
+    /// line.
+    fn read_header_directive(&mut self, line: &str) -> bool {
+        let Some(command) = line.strip_prefix('#') else {
+            return false;
+        };
+        self.header_scroll_mode = match self.builtin_directives.parse_directive(command) {
+            Some(Directive::BmScroll) => ScrollMode::BmScroll,
+            Some(Directive::HbScroll) => ScrollMode::HbScroll,
+            Some(Directive::NmScroll) => ScrollMode::Normal,
+            _ => return false,
+        };
+        true
+    }
+
+    /// Reports a line outside of a chart that is not a `KEY:VALUE` pair.
+    fn report_unparsed_line(&mut self, line: &str) -> Result<(), TjaError> {
+        if line.starts_with('#') {
+            if is_directive(line, "END") {
+                self.report(
+                    Severity::Warning,
+                    DiagnosticCode::UnexpectedEnd,
+                    0,
+                    line,
+                    "`#END` without matching `#START` is ignored".to_string(),
+                )
             } else {
-                self.process_notes(line)?;
+                self.report(
+                    Severity::Warning,
+                    DiagnosticCode::DirectiveOutsideChart,
+                    0,
+                    line,
+                    format!("directive `{}` outside of a chart is ignored", line),
+                )
+            }
+        } else if is_notes_line(line) {
+            self.report(
+                Severity::Warning,
+                DiagnosticCode::NotesOutsideChart,
+                0,
+                line,
+                "notes outside of `#START`/`#END` are ignored".to_string(),
+            )
+        } else {
+            self.report(
+                Severity::Warning,
+                DiagnosticCode::MalformedHeader,
+                0,
+                line,
+                format!("`{}` is not a `KEY:VALUE` pair and is ignored", line),
+            )
+        }
+    }
+
+    /// Reports a roll or balloon that was still open when its chart or
+    /// branch ended.
+    fn check_open_roll(&mut self, text: &str) -> Result<(), TjaError> {
+        if let Some(location) = self.open_roll.take() {
+            self.report_at(
+                Severity::Warning,
+                DiagnosticCode::UnterminatedRoll,
+                location,
+                "",
+                format!("roll or balloon is not terminated before {}", text),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn report_missing_end(&mut self) -> Result<(), TjaError> {
+        self.check_open_roll("the end of the chart")?;
+        self.report_at(
+            Severity::Warning,
+            DiagnosticCode::MissingEnd,
+            self.chart_start,
+            "#START",
+            "chart is not terminated by `#END`".to_string(),
+        )
+    }
+
+    /// Reports a chart with more balloon notes than `BALLOON:` counts.
+    fn check_balloon_counts(&mut self, line: &str) -> Result<(), TjaError> {
+        if let Some(chart) = self.charts.last() {
+            let balloons = chart
+                .segments
+                .iter()
+                .flat_map(|segment| &segment.notes)
+                .filter(|note| note.note_type.is_balloon())
+                .count();
+            if balloons > chart.balloons.len() {
+                let message = format!(
+                    "chart has {} balloon notes but `BALLOON:` lists {} counts",
+                    balloons,
+                    chart.balloons.len()
+                );
+                self.report(
+                    Severity::Warning,
+                    DiagnosticCode::BalloonCountMismatch,
+                    0,
+                    line,
+                    message,
+                )?;
             }
         }
         Ok(())
     }
 
-    fn handle_metadata_or_header(&mut self, line: &str) {
+    fn handle_metadata_or_header(&mut self, line: &str) -> Result<(), TjaError> {
         if let Some((key, value)) = self.parse_metadata_or_header(line) {
             if self.header_keys.contains(&key) {
                 if key == "BALLOON" {
-                    let cleaned_value = value
+                    let counts: Vec<_> = value
                         .split(',')
+                        .filter(|num| !num.trim().is_empty())
+                        .collect();
+                    let cleaned = counts
+                        .iter()
                         .filter_map(|num| num.trim().parse::<i32>().ok())
                         .map(|num| num.to_string())
-                        .collect::<Vec<_>>()
-                        .join(",");
-                    self.current_headers.insert(key.clone(), cleaned_value);
+                        .collect::<Vec<_>>();
+                    if cleaned.len() != counts.len() {
+                        self.report_invalid_value(line, &key, &value)?;
+                    }
+                    self.current_headers.insert(key.clone(), cleaned.join(","));
                 } else {
+                    let valid = match key.as_str() {
+                        "COURSE" => value.parse::<Course>().is_ok(),
+                        "LEVEL" => value.parse::<Level>().is_ok(),
+                        "LIFE" => value.parse::<u32>().is_ok(),
+                        "SIDE" => value.parse::<Side>().is_ok(),
+                        key if key.starts_with("EXAM") => value.parse::<Exam>().is_ok(),
+                        _ => true,
+                    };
+                    if !valid {
+                        self.report_invalid_value(line, &key, &value)?;
+                    }
                     self.current_headers.insert(key.clone(), value.clone());
                 }
                 if self.inheritable_header_keys.contains(&key) {
                     self.inherited_headers.insert(key, value);
                 }
+            } else if self.options.retain_unknown_headers {
+                self.report(
+                    Severity::Info,
+                    DiagnosticCode::UnknownHeader,
+                    0,
+                    line,
+                    format!("unrecognized header `{}` is kept", key),
+                )?;
+                self.current_headers.insert(key, value);
+            } else {
+                self.report(
+                    Severity::Info,
+                    DiagnosticCode::UnknownHeader,
+                    0,
+                    line,
+                    format!("unrecognized header `{}` is ignored", key),
+                )?;
             }
+        } else {
+            self.report_unparsed_line(line)?;
         }
+        Ok(())
+    }
+
+    fn new_metadata(&self, data: HashMap<String, String>) -> Metadata {
+        let mut unknown_keys: Vec<_> = data
+            .keys()
+            .filter(|key| !self.metadata_keys.contains(*key))
+            .cloned()
+            .collect();
+        unknown_keys.sort();
+        let mut metadata = Metadata::new(data);
+        metadata.unknown_keys = unknown_keys;
+        metadata
     }
 
     fn parse_metadata_or_header(&self, line: &str) -> Option<(String, String)> {
@@ -280,188 +781,443 @@ impl TJAParser {
         })
     }
 
-    fn process_directive(&mut self, command: &str) -> Result<(), String> {
-        if let Some(directive) = self.directive_handler.parse_directive(command) {
-            let state = self
-                .state
-                .as_mut()
-                .ok_or_else(|| "Parser state not initialized".to_string())?;
+    fn process_directive(&mut self, command: &str) -> Result<(), TjaError> {
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        if let Some(handler) = self.custom_directives.get(name) {
+            let Some(state) = self.state.as_mut() else {
+                return Err(self.state_error("parser state not initialized"));
+            };
+            return match handler.handle(args.trim(), state) {
+                Ok(Some(directive)) => self.apply_directive(directive, command),
+                Ok(None) => Ok(()),
+                Err(message) => self.report(
+                    Severity::Warning,
+                    DiagnosticCode::MalformedDirective,
+                    0,
+                    &format!("#{}", command),
+                    message,
+                ),
+            };
+        }
 
-            match directive {
-                Directive::Start(player) => {
-                    let player_num = match player.as_deref() {
-                        Some("P1") => 1,
-                        Some("P2") => 2,
-                        _ => 0,
-                    };
+        if let Some(directive) = self.builtin_directives.parse_directive(command) {
+            self.apply_directive(directive, command)
+        } else {
+            self.queue_event(Directive::Raw {
+                name: name.to_string(),
+                args: args.trim().to_string(),
+            });
+            let text = format!("#{}", command);
+            if self
+                .builtin_directives
+                .get_directive_type(command)
+                .is_some()
+            {
+                self.report(
+                    Severity::Warning,
+                    DiagnosticCode::MalformedDirective,
+                    0,
+                    &text,
+                    format!("malformed directive `{}` is ignored", text),
+                )
+            } else {
+                self.report(
+                    Severity::Warning,
+                    DiagnosticCode::UnknownDirective,
+                    0,
+                    &text,
+                    format!("unknown directive `{}` is ignored", text),
+                )
+            }
+        }
+    }
 
-                    let mut merged_headers = self.inherited_headers.clone();
-                    merged_headers.extend(self.current_headers.clone());
-
-                    let chart = Chart::new(merged_headers, player_num);
-                    self.charts.push(chart);
-                    state.parsing_chart = true;
-                    state.timestamp = -self.metadata.as_ref().unwrap().offset;
-                    state.bpm = self.state_internal.as_ref().unwrap().bpm;
-                    state.scroll = self.state_internal.as_ref().unwrap().scroll;
-                    state.gogo = self.state_internal.as_ref().unwrap().gogo;
-                    state.barline = self.state_internal.as_ref().unwrap().barline;
-                    state.measure_num = self.state_internal.as_ref().unwrap().measure_num;
-                    state.measure_den = self.state_internal.as_ref().unwrap().measure_den;
-                    state.branch_condition = self
-                        .state_internal
-                        .as_ref()
-                        .unwrap()
-                        .branch_condition
-                        .clone();
-                    state.current_branch =
-                        self.state_internal.as_ref().unwrap().current_branch.clone();
-                    state.delay = self.state_internal.as_ref().unwrap().delay;
-                    state.timestamp_branch_start =
-                        self.state_internal.as_ref().unwrap().timestamp_branch_start;
-                    state.current_segment = None;
-                }
-                Directive::End => {
-                    if let (Some(segment), Some(current_chart)) =
-                        (state.current_segment.take(), self.charts.last_mut())
-                    {
-                        if let Some(parsed_segment) = calculate_note_timestamp(
-                            state,
-                            segment,
-                            self.mode == ParsingMode::FullWithBlanks,
-                        ) {
-                            current_chart.segments.push(parsed_segment);
-                        }
-                    }
+    fn apply_directive(&mut self, directive: Directive, command: &str) -> Result<(), TjaError> {
+        if self.state.is_none() {
+            return Err(self.state_error("parser state not initialized"));
+        }
+        let offset = match &self.metadata {
+            Some(metadata) => metadata.offset,
+            None if matches!(directive, Directive::Start(_)) => {
+                return Err(self.state_error("`#START` before metadata"));
+            }
+            None => 0.0,
+        };
+        match &directive {
+            Directive::Start(_) if self.state.as_ref().unwrap().parsing_chart => {
+                self.report_missing_end()?;
+            }
+            Directive::BpmChange(bpm) if *bpm <= 0.0 || !bpm.is_finite() => {
+                self.report(
+                    Severity::Warning,
+                    DiagnosticCode::MalformedDirective,
+                    0,
+                    &format!("#{}", command),
+                    format!("tempo `{}` is not a positive number", bpm),
+                )?;
+            }
+            Directive::BranchStart(condition) if condition.parse::<BranchCondition>().is_err() => {
+                self.report(
+                    Severity::Warning,
+                    DiagnosticCode::MalformedDirective,
+                    0,
+                    &format!("#{}", command),
+                    format!("branch condition `{}` is not understood", condition),
+                )?;
+            }
+            Directive::End
+            | Directive::BranchEnd
+            | Directive::BranchNormal
+            | Directive::BranchExpert
+            | Directive::BranchMaster => {
+                self.check_open_roll(&format!("`#{}`", command))?;
+            }
+            _ => {}
+        }
+        let is_end = matches!(directive, Directive::End);
+        if let Directive::Start(_) = directive {
+            self.chart_start = self.location(0);
+            self.flush_pending_events();
+        }
+        let event = directive.clone();
+        let legacy_delay = self.options.legacy_delay;
+        let state = self.state.as_mut().unwrap();
 
-                    state.parsing_chart = false;
-                    state.branch_condition = None;
-                }
-                Directive::BpmChange(bpm) => {
-                    state.bpm = bpm;
-                }
-                Directive::Scroll(value) => {
-                    state.scroll = value;
-                }
-                Directive::GogoStart => {
-                    state.gogo = true;
-                }
-                Directive::GogoEnd => {
-                    state.gogo = false;
-                }
-                Directive::BarlineOff => {
-                    state.barline = false;
-                }
-                Directive::BarlineOn => {
-                    state.barline = true;
-                }
-                Directive::BranchStart(condition) => {
-                    state.branch_condition = Some(condition);
-                    state.timestamp_branch_start = state.timestamp;
-                }
-                Directive::BranchEnd => {
-                    state.parsing_chart = false;
-                    state.branch_condition = None;
-                    state.current_branch = None;
-                }
-                Directive::Measure(num, den) => {
-                    state.measure_num = num;
-                    state.measure_den = den;
-                }
-                Directive::Delay(value) => {
-                    state.delay += value;
-                }
-                Directive::Section => {
-                    // Handle section if needed, i don't remember what's this
+        match directive {
+            Directive::Start(player) => {
+                let player_num = match player.as_deref() {
+                    Some("P1") => 1,
+                    Some("P2") => 2,
+                    _ => 0,
+                };
+
+                let mut merged_headers = self.inherited_headers.clone();
+                merged_headers.extend(self.current_headers.clone());
+
+                let mut unknown_headers: Vec<_> = merged_headers
+                    .keys()
+                    .filter(|key| !self.header_keys.contains(*key))
+                    .cloned()
+                    .collect();
+                unknown_headers.sort();
+                let mut chart = Chart::new(merged_headers, player_num);
+                chart.unknown_headers = unknown_headers;
+                chart.scroll_mode = self.header_scroll_mode;
+                if self.options.source_spans {
+                    chart.span = Some(SourceRange::new(self.chart_start, self.chart_start));
                 }
-                Directive::BranchNormal => {
-                    state.current_branch = Some("N".to_string());
-                    state.timestamp = state.timestamp_branch_start;
+                self.charts.push(chart);
+                state.parsing_chart = true;
+                state.timestamp = -offset;
+                state.bpm = self.state_internal.as_ref().unwrap().bpm;
+                state.scroll = self.state_internal.as_ref().unwrap().scroll;
+                state.scroll_imag = self.state_internal.as_ref().unwrap().scroll_imag;
+                state.gogo = self.state_internal.as_ref().unwrap().gogo;
+                state.barline = self.state_internal.as_ref().unwrap().barline;
+                state.measure_num = self.state_internal.as_ref().unwrap().measure_num;
+                state.measure_den = self.state_internal.as_ref().unwrap().measure_den;
+                state.branch_condition = self
+                    .state_internal
+                    .as_ref()
+                    .unwrap()
+                    .branch_condition
+                    .clone();
+                state.current_branch = self.state_internal.as_ref().unwrap().current_branch.clone();
+                state.delay = self.state_internal.as_ref().unwrap().delay;
+                state.current_segment = None;
+                state.measure_index = 0;
+                state.beat = Rational::ZERO;
+                state.tempo = TempoAnchor::new(state.timestamp, state.bpm);
+                state.save_branch_start();
+                state.branch_section = None;
+                state.branch_sections = 0;
+            }
+            Directive::End => {
+                if let (Some(segment), Some(current_chart)) =
+                    (state.current_segment.take(), self.charts.last_mut())
+                {
+                    resolve_events(
+                        state,
+                        &segment,
+                        &mut self.pending_events,
+                        &mut current_chart.events,
+                    );
+                    push_measure(
+                        state,
+                        segment,
+                        self.mode == ParsingMode::FullWithBlanks,
+                        current_chart,
+                    );
                 }
-                Directive::BranchMaster => {
-                    state.current_branch = Some("M".to_string());
-                    state.timestamp = state.timestamp_branch_start;
+
+                state.parsing_chart = false;
+                state.branch_condition = None;
+                state.branch_section = None;
+            }
+            Directive::BpmChange(bpm) => {
+                state.bpm = bpm;
+            }
+            Directive::Scroll(value) => {
+                state.scroll = value;
+                state.scroll_imag = 0.0;
+            }
+            Directive::ComplexScroll(real, imag) => {
+                state.scroll = real;
+                state.scroll_imag = imag;
+            }
+            Directive::GogoStart => {
+                state.gogo = true;
+            }
+            Directive::GogoEnd => {
+                state.gogo = false;
+            }
+            Directive::BarlineOff => {
+                state.barline = false;
+            }
+            Directive::BarlineOn => {
+                state.barline = true;
+            }
+            Directive::BranchStart(condition) => {
+                state.branch_condition = Some(condition);
+                state.branch_section = Some(state.branch_sections);
+                state.branch_sections += 1;
+                state.save_branch_start();
+            }
+            Directive::BranchEnd => {
+                state.branch_condition = None;
+                state.branch_section = None;
+                state.current_branch = None;
+            }
+            Directive::Measure(num, den) => {
+                state.measure_num = num;
+                state.measure_den = den;
+            }
+            Directive::Delay(value) => {
+                state.delay += value;
+            }
+            Directive::BmScroll | Directive::HbScroll | Directive::NmScroll => {
+                if let Some(chart) = self.charts.last_mut() {
+                    chart.scroll_mode = match directive {
+                        Directive::BmScroll => ScrollMode::BmScroll,
+                        Directive::HbScroll => ScrollMode::HbScroll,
+                        _ => ScrollMode::Normal,
+                    };
                 }
-                Directive::BranchExpert => {
-                    state.current_branch = Some("E".to_string());
-                    state.timestamp = state.timestamp_branch_start;
+            }
+            Directive::NextSong(_) => {
+                // Songs are split from `Chart::events`, see `Chart::songs`
+            }
+            Directive::JposScroll { .. } | Directive::Direction(_) | Directive::Sudden { .. } => {
+                // Only drawn by simulators, see `Chart::events`
+            }
+            Directive::Section | Directive::LevelHold => {
+                // Only affect branch decisions, see `Chart::simulate_branches`
+            }
+            Directive::Raw { .. } | Directive::Custom { .. } => {}
+            Directive::BranchNormal => {
+                state.restore_branch_start("N", legacy_delay);
+            }
+            Directive::BranchMaster => {
+                state.restore_branch_start("M", legacy_delay);
+            }
+            Directive::BranchExpert => {
+                state.restore_branch_start("E", legacy_delay);
+            }
+        }
+
+        match event {
+            Directive::Start(_) | Directive::End => {
+                self.flush_pending_events();
+                let state = self.state.as_ref().unwrap();
+                let event = ChartEvent {
+                    timestamp: state.timestamp + state.delay,
+                    beat: state.beat,
+                    measure_index: state.measure_index,
+                    position: 0.0,
+                    branch: state.current_branch.clone(),
+                    directive: event,
+                };
+                if let Some(chart) = self.charts.last_mut() {
+                    chart.events.push(event);
                 }
             }
+            directive => self.queue_event(directive),
+        }
+
+        if is_end {
+            self.check_balloon_counts(&format!("#{}", command))?;
         }
         Ok(())
     }
 
-    fn process_notes(&mut self, notes_str: &str) -> Result<(), String> {
-        let state = self
-            .state
-            .as_mut()
-            .ok_or_else(|| "Parser state not initialized".to_string())?;
+    /// Holds a directive of the current chart until its measure is complete.
+    fn queue_event(&mut self, directive: Directive) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        if state.parsing_state != ParsingState::Notes || self.charts.is_empty() {
+            return;
+        }
+        self.pending_events.push(PendingEvent {
+            note_index: state.current_segment.as_ref().map_or(0, |s| s.notes.len()),
+            delay: state.delay,
+            branch: state.current_branch.clone(),
+            directive,
+        });
+    }
+
+    /// Places directives that no comma will resolve, such as those right
+    /// before `#END`, at the current time.
+    fn flush_pending_events(&mut self) {
+        if let (Some(state), Some(chart)) = (&self.state, self.charts.last_mut()) {
+            let measure = Segment::new(0.0, 0, 1, true, None, None);
+            resolve_events(state, &measure, &mut self.pending_events, &mut chart.events);
+        }
+        self.pending_events.clear();
+    }
 
-        if !state.parsing_chart {
-            return Ok(());
+    fn process_notes(&mut self, notes_str: &str) -> Result<(), TjaError> {
+        match &self.state {
+            None => return Err(self.state_error("parser state not initialized")),
+            Some(state) if !state.parsing_chart => {
+                return self.report(
+                    Severity::Warning,
+                    DiagnosticCode::NotesOutsideChart,
+                    0,
+                    notes_str,
+                    "notes outside of a chart are ignored".to_string(),
+                );
+            }
+            Some(_) => {}
         }
 
-        let current_chart = self
-            .charts
-            .last_mut()
-            .ok_or_else(|| "No current chart".to_string())?;
-
-        for b in notes_str.as_bytes() {
-            match b {
-                b'0'..=b'9' => {
-                    if let Some(note_type) = NoteType::from_byte(*b) {
-                        let note = Note {
-                            note_type,
-                            timestamp: -1.0,
-                            bpm: state.bpm,
-                            delay: state.delay,
-                            scroll: state.scroll,
-                            gogo: state.gogo,
-                        };
-
-                        if state.current_segment.is_none() {
-                            state.current_segment = Some(Segment::new(
-                                state.timestamp + state.delay,
-                                state.measure_num,
-                                state.measure_den,
-                                state.barline,
-                                state.current_branch.clone(),
-                                state.branch_condition.clone(),
-                            ));
-                            state.current_segment.as_mut().unwrap().notes.reserve(64);
+        let (line, column) = (self.line, self.column);
+        let source_spans = self.options.source_spans;
+        let extended_notes = self.options.extended_notes;
+        let mut open_roll = self.open_roll;
+        let state = self.state.as_mut().unwrap();
+        let current_chart = self.charts.last_mut().unwrap();
+        let pending_events = &mut self.pending_events;
+        let mut invalid = Vec::new();
+        let mut chars = 0;
+
+        for (i, b) in notes_str.as_bytes().iter().enumerate() {
+            let location = Location::new(line, column + chars);
+            if notes_str.is_char_boundary(i) {
+                chars += 1;
+            }
+            let note_type = match b {
+                b'0'..=b'9' => NoteType::from_byte(*b),
+                b'A'..=b'I' if extended_notes => NoteType::from_extended_byte(*b),
+                _ => None,
+            };
+            match (b, note_type) {
+                (_, Some(note_type)) => {
+                    if note_type == NoteType::EndOf {
+                        open_roll = None;
+                    } else if note_type.is_roll() && open_roll.is_none() {
+                        open_roll = Some(location);
+                    }
+                    let note = Note {
+                        note_type,
+                        timestamp: -1.0,
+                        bpm: state.bpm,
+                        delay: state.delay,
+                        scroll: state.scroll,
+                        scroll_imag: state.scroll_imag,
+                        gogo: state.gogo,
+                        measure_index: state.measure_index,
+                        subdivision: 0,
+                        slot: 0,
+                        position: Rational::ZERO,
+                        beat: Rational::ZERO,
+                        location: source_spans.then_some(location),
+                    };
+
+                    if state.current_segment.is_none() {
+                        state.current_segment = Some(Segment::new(
+                            state.timestamp + state.delay,
+                            state.measure_num,
+                            state.measure_den,
+                            state.barline,
+                            state.current_branch.clone(),
+                            state.branch_condition.clone(),
+                        ));
+                        let segment = state.current_segment.as_mut().unwrap();
+                        segment.branch_section = state.branch_section;
+                        segment.notes.reserve(64);
+                        if source_spans {
+                            segment.span = Some(SourceRange::new(location, location));
                         }
-                        if let Some(segment) = &mut state.current_segment {
-                            segment.notes.push(note);
+                    }
+                    if let Some(segment) = &mut state.current_segment {
+                        segment.notes.push(note);
+                        if let Some(span) = &mut segment.span {
+                            span.end = location;
                         }
                     }
                 }
-                b',' => {
-                    let segment = state.current_segment.take().unwrap_or_else(|| {
-                        Segment::new(
+                (b',', None) => {
+                    let mut segment = state.current_segment.take().unwrap_or_else(|| {
+                        let mut segment = Segment::new(
                             state.timestamp + state.delay,
                             state.measure_num,
                             state.measure_den,
                             state.barline,
                             state.current_branch.clone(),
                             state.branch_condition.clone(),
-                        )
+                        );
+                        segment.branch_section = state.branch_section;
+                        segment
                     });
+                    if source_spans {
+                        let start = segment.span.map_or(location, |span| span.start);
+                        segment.span = Some(SourceRange::new(start, location));
+                    }
 
-                    if let Some(parsed_segment) = calculate_note_timestamp(
+                    resolve_events(state, &segment, pending_events, &mut current_chart.events);
+                    push_measure(
                         state,
                         segment,
                         self.mode == ParsingMode::FullWithBlanks,
-                    ) {
-                        current_chart.segments.push(parsed_segment);
-                    }
+                        current_chart,
+                    );
+                    state.measure_index += 1;
                 }
+                (b, None) if b.is_ascii_whitespace() => {}
+                _ if notes_str.is_char_boundary(i) => invalid.push(i),
                 _ => {}
             }
         }
 
+        self.open_roll = open_roll;
+
+        for i in invalid {
+            let offset = notes_str[..i].chars().count();
+            let c = notes_str[i..].chars().next().unwrap();
+            self.report(
+                Severity::Warning,
+                DiagnosticCode::InvalidNoteCharacter,
+                offset,
+                &notes_str[i..i + c.len_utf8()],
+                format!("invalid note character `{}` is ignored", c),
+            )?;
+        }
+
         Ok(())
     }
 
+    /// Encoding chosen by the last [`TJAParser::parse_bytes`] or
+    /// [`TJAParser::parse_file`] call.
+    pub fn encoding(&self) -> Option<TextEncoding> {
+        self.encoding
+    }
+
+    /// Everything the parser ignored or reinterpreted so far.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
     pub fn get_metadata(&self) -> Option<&Metadata> {
         self.metadata.as_ref()
     }
@@ -503,11 +1259,17 @@ impl TJAParser {
         double_charts
     }
 
+    /// Panics if nothing has been parsed yet; see [`TJAParser::try_get_parsed_tja`].
     pub fn get_parsed_tja(&self) -> ParsedTJA {
-        ParsedTJA {
-            metadata: self.metadata.clone().unwrap(),
+        self.try_get_parsed_tja()
+            .expect("get_parsed_tja called before parse_str")
+    }
+
+    pub fn try_get_parsed_tja(&self) -> Result<ParsedTJA, TjaError> {
+        Ok(ParsedTJA {
+            metadata: self.metadata.clone().ok_or(TjaError::MissingMetadata)?,
             charts: self.charts.clone(),
-        }
+        })
     }
 
     pub fn add_metadata_key(&mut self, key: &str) {
@@ -521,6 +1283,12 @@ impl TJAParser {
     pub fn add_inheritable_header_key(&mut self, key: &str) {
         self.inheritable_header_keys.insert(key.to_string());
     }
+
+    /// Handles `#name` with `handler`, matching the name case-insensitively.
+    /// Registered handlers take precedence over the built-in directives.
+    pub fn add_directive_handler<H: DirectiveHandler + 'static>(&mut self, name: &str, handler: H) {
+        self.custom_directives.insert(name, Arc::new(handler));
+    }
 }
 
 fn normalize_line(line: &str) -> Option<&str> {
@@ -537,25 +1305,129 @@ fn normalize_line(line: &str) -> Option<&str> {
     }
 }
 
+/// Whether `line` is the directive `name`, such as `#start p1` for
+/// `START`. Directive names are read in any case.
+pub(crate) fn is_directive(line: &str, name: &str) -> bool {
+    let Some(command) = line.strip_prefix('#') else {
+        return false;
+    };
+    let end = command.find(char::is_whitespace).unwrap_or(command.len());
+    command[..end].eq_ignore_ascii_case(name)
+}
+
+/// Whether a line outside of a chart looks like a measure of notes.
+fn is_notes_line(line: &str) -> bool {
+    line.bytes()
+        .all(|b| b.is_ascii_digit() || b == b',' || b.is_ascii_whitespace())
+}
+
+/// 1-based character column at which `trimmed`, a subslice of `raw`, begins.
+fn column_of(raw: &str, trimmed: &str) -> usize {
+    let offset = trimmed.as_ptr() as usize - raw.as_ptr() as usize;
+    raw[..offset].chars().count() + 1
+}
+
+/// Exact beat and time of every note of `segment`, which must not have
+/// been timed yet, followed by those of its end, and the tempo in effect
+/// at its end.
+///
+/// Each note keeps the tempo up to the next one, and an empty measure
+/// takes the tempo in effect at its comma.
+fn measure_timing(state: &ParserState, segment: &Segment) -> (Vec<(Rational, f64)>, TempoAnchor) {
+    let count = segment.notes.len();
+    let length = Rational::new(
+        4 * segment.measure_num as i64,
+        segment.measure_den.max(1) as i64,
+    );
+    let mut tempo = state.tempo;
+    let mut times = Vec::with_capacity(count + 1);
+    for (i, note) in segment.notes.iter().enumerate() {
+        let beat = state.beat + length * Rational::new(i as i64, count as i64);
+        tempo = tempo.with_bpm(beat, note.bpm);
+        times.push((beat, tempo.time_at(beat)));
+    }
+    if count == 0 {
+        tempo = tempo.with_bpm(state.beat, state.bpm);
+    }
+    let end = state.beat + length;
+    times.push((end, tempo.time_at(end)));
+    (times, tempo)
+}
+
+/// Places the pending directives of `segment`, which must not have been
+/// timed yet, on the timeline.
+fn resolve_events(
+    state: &ParserState,
+    segment: &Segment,
+    pending: &mut Vec<PendingEvent>,
+    events: &mut Vec<ChartEvent>,
+) {
+    let count = segment.notes.len();
+    let (times, _) = measure_timing(state, segment);
+    for event in pending.drain(..) {
+        // Directives of an empty measure take effect at its start
+        let (beat, time) = match count {
+            0 => (state.beat, state.timestamp),
+            _ => times[event.note_index.min(count)],
+        };
+        events.push(ChartEvent {
+            timestamp: time + event.delay,
+            beat,
+            measure_index: state.measure_index,
+            position: if count == 0 {
+                0.0
+            } else {
+                event.note_index as f64 / count as f64
+            },
+            branch: event.branch,
+            directive: event.directive,
+        });
+    }
+}
+
+/// Times `segment`, records its measure in `chart.measures` and keeps it in
+/// `chart.segments` unless it is blank.
+fn push_measure(state: &mut ParserState, segment: Segment, keep_blanks: bool, chart: &mut Chart) {
+    let mut measure = Measure {
+        timestamp: segment.timestamp,
+        end: segment.timestamp,
+        beat: state.beat,
+        measure_index: state.measure_index,
+        measure_num: segment.measure_num,
+        measure_den: segment.measure_den,
+        barline: segment.barline,
+        // An empty measure only has its comma, where `state` still applies
+        gogo: segment.notes.first().map_or(state.gogo, |note| note.gogo),
+        bpm: segment.notes.first().map_or(state.bpm, |note| note.bpm),
+        delay: segment.notes.first().map_or(state.delay, |note| note.delay),
+        branch: segment.branch.clone(),
+        branch_section: segment.branch_section,
+    };
+    if let Some(segment) = calculate_note_timestamp(state, segment, keep_blanks) {
+        chart.segments.push(segment);
+    }
+    measure.end = state.timestamp + state.delay;
+    chart.measures.push(measure);
+}
+
 fn calculate_note_timestamp(
     state: &mut ParserState,
     mut segment: Segment,
     keep_blanks: bool,
 ) -> Option<Segment> {
     let count = segment.notes.len();
+    let (times, tempo) = measure_timing(state, &segment);
 
-    if count > 0 {
-        let base =
-            60.0 * segment.measure_num as f64 / segment.measure_den as f64 * 4.0 / count as f64;
-
-        for note in segment.notes.iter_mut() {
-            note.timestamp = state.timestamp + note.delay;
-            state.timestamp += base / note.bpm;
-        }
-    } else {
-        state.timestamp +=
-            60.0 / state.bpm * segment.measure_num as f64 / segment.measure_den as f64 * 4.0;
+    for (i, note) in segment.notes.iter_mut().enumerate() {
+        let (beat, time) = times[i];
+        note.timestamp = time + note.delay;
+        note.subdivision = count;
+        note.slot = i;
+        note.position = Rational::new(i as i64, count as i64);
+        note.beat = beat;
     }
+    (state.beat, state.timestamp) = times[count];
+    state.tempo = tempo;
 
     if !keep_blanks {
         segment
@@ -1,5 +1,5 @@
 use crate::synthesize::{synthesize_tja_audio, AudioData};
-use crate::{types::*, ParsingMode, TJAParser};
+use crate::{types::*, ParserOptions, ParsingMode, SourceRange, TJAParser};
 use pyo3::prelude::*;
 use pyo3::types::{PyDict, PyList};
 use serde::Serialize;
@@ -38,15 +38,34 @@ fn json_to_py(py: Python, value: &serde_json::Value) -> PyObject {
     }
 }
 
+/// `((line, column), (line, column))` of the first and last character.
+type PySpan = ((usize, usize), (usize, usize));
+
+fn py_span(range: SourceRange) -> PySpan {
+    (
+        (range.start.line, range.start.column),
+        (range.end.line, range.end.column),
+    )
+}
+
 #[pyclass(get_all)]
 #[derive(Clone, Debug, Serialize)]
 struct PyNote {
     note_type: String,
     timestamp: f64,
     scroll: f64,
+    scroll_imag: f64,
     delay: f64,
     bpm: f64,
     gogo: bool,
+    measure_index: usize,
+    subdivision: usize,
+    slot: usize,
+    /// Exact fractions such as `"3/4"`.
+    position: String,
+    beat: String,
+    /// `(line, column)` of the note character.
+    location: Option<(usize, usize)>,
 }
 
 #[pymethods]
@@ -75,7 +94,9 @@ struct PySegment {
     barline: bool,
     branch: Option<String>,
     branch_condition: Option<String>,
+    branch_section: Option<usize>,
     notes: Vec<PyNote>,
+    span: Option<PySpan>,
 }
 
 #[pymethods]
@@ -103,7 +124,12 @@ struct PyChart {
     level: Option<i32>,
     balloons: Vec<i32>,
     headers: HashMap<String, String>,
+    unknown_headers: Vec<String>,
     segments: Vec<PySegment>,
+    life: Option<u32>,
+    side: Option<String>,
+    scroll_mode: String,
+    span: Option<PySpan>,
 }
 
 #[pymethods]
@@ -166,6 +192,7 @@ impl PyParsedTJA {
                     level: c.level.map(|l| crate::types::Level(l)),
                     balloons: c.balloons.clone(),
                     headers: c.headers.clone(),
+                    unknown_headers: c.unknown_headers.clone(),
                     segments: c
                         .segments
                         .iter()
@@ -176,6 +203,7 @@ impl PyParsedTJA {
                             barline: s.barline,
                             branch: s.branch.clone(),
                             branch_condition: s.branch_condition.clone(),
+                            branch_section: s.branch_section,
                             notes: s
                                 .notes
                                 .iter()
@@ -191,17 +219,40 @@ impl PyParsedTJA {
                                         "Balloon" => crate::types::NoteType::Balloon,
                                         "EndOf" => crate::types::NoteType::EndOf,
                                         "BalloonAlt" => crate::types::NoteType::BalloonAlt,
+                                        "DonBoth" => crate::types::NoteType::DonBoth,
+                                        "KaBoth" => crate::types::NoteType::KaBoth,
+                                        "Bomb" => crate::types::NoteType::Bomb,
+                                        "FuseRoll" => crate::types::NoteType::FuseRoll,
+                                        "Adlib" => crate::types::NoteType::Adlib,
+                                        "Purple" => crate::types::NoteType::Purple,
+                                        "RollBigH" => crate::types::NoteType::RollBigH,
+                                        "RollBigI" => crate::types::NoteType::RollBigI,
                                         _ => crate::types::NoteType::Empty,
                                     },
                                     timestamp: n.timestamp,
                                     scroll: n.scroll,
+                                    scroll_imag: n.scroll_imag,
                                     delay: n.delay,
                                     bpm: n.bpm,
                                     gogo: n.gogo,
+                                    measure_index: n.measure_index,
+                                    subdivision: n.subdivision,
+                                    slot: n.slot,
+                                    position: n.position.parse().unwrap_or_default(),
+                                    beat: n.beat.parse().unwrap_or_default(),
+                                    location: None,
                                 })
                                 .collect(),
+                            span: None,
                         })
                         .collect(),
+                    measures: Vec::new(),
+                    exams: Vec::new(),
+                    life: None,
+                    side: None,
+                    scroll_mode: Default::default(),
+                    events: Vec::new(),
+                    span: None,
                 })
                 .collect(),
         };
@@ -226,9 +277,16 @@ impl From<Note> for PyNote {
             note_type: format!("{:?}", note.note_type),
             timestamp: note.timestamp,
             scroll: note.scroll,
+            scroll_imag: note.scroll_imag,
             delay: note.delay,
             bpm: note.bpm,
             gogo: note.gogo,
+            measure_index: note.measure_index,
+            subdivision: note.subdivision,
+            slot: note.slot,
+            position: note.position.to_string(),
+            beat: note.beat.to_string(),
+            location: note.location.map(|l| (l.line, l.column)),
         }
     }
 }
@@ -242,7 +300,9 @@ impl From<Segment> for PySegment {
             barline: segment.barline,
             branch: segment.branch,
             branch_condition: segment.branch_condition,
+            branch_section: segment.branch_section,
             notes: segment.notes.into_iter().map(PyNote::from).collect(),
+            span: segment.span.map(py_span),
         }
     }
 }
@@ -255,7 +315,12 @@ impl From<Chart> for PyChart {
             level: chart.level.map(|l| l.value()),
             balloons: chart.balloons,
             headers: chart.headers,
+            unknown_headers: chart.unknown_headers,
             segments: chart.segments.into_iter().map(PySegment::from).collect(),
+            life: chart.life,
+            side: chart.side.map(|s| format!("{:?}", s)),
+            scroll_mode: format!("{:?}", chart.scroll_mode),
+            span: chart.span.map(py_span),
         }
     }
 }
@@ -336,6 +401,8 @@ pub enum PyCourse {
     Hard,
     Oni,
     Ura,
+    Tower,
+    Dan,
 }
 
 #[pymethods]
@@ -357,6 +424,8 @@ impl From<PyCourse> for Course {
             PyCourse::Hard => Course::Hard,
             PyCourse::Oni => Course::Oni,
             PyCourse::Ura => Course::Ura,
+            PyCourse::Tower => Course::Tower,
+            PyCourse::Dan => Course::Dan,
         }
     }
 }
@@ -394,12 +463,33 @@ impl From<PyParsingMode> for ParsingMode {
 }
 
 #[pyfunction]
-#[pyo3(signature = (content, mode = PyParsingMode::Full))]
-pub fn parse_tja(content: &str, mode: PyParsingMode) -> PyResult<PyParsedTJA> {
-    let mut parser = TJAParser::with_mode(mode.into());
+#[pyo3(signature = (
+    content,
+    mode = PyParsingMode::Full,
+    source_spans = false,
+    retain_unknown_headers = false,
+    extended_notes = false,
+    legacy_delay = false
+))]
+pub fn parse_tja(
+    content: &str,
+    mode: PyParsingMode,
+    source_spans: bool,
+    retain_unknown_headers: bool,
+    extended_notes: bool,
+    legacy_delay: bool,
+) -> PyResult<PyParsedTJA> {
+    let options = ParserOptions {
+        source_spans,
+        retain_unknown_headers,
+        extended_notes,
+        legacy_delay,
+        ..Default::default()
+    };
+    let mut parser = TJAParser::with_options(mode.into(), options);
     parser
         .parse_str(content)
-        .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(e))?;
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(e.to_string()))?;
 
     let parsed = parser.get_parsed_tja();
     Ok(PyParsedTJA::from(parsed))
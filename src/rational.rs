@@ -0,0 +1,195 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::ops::{Add, Div, Mul, Sub};
+
+/// An exact fraction, always kept in lowest terms with a positive
+/// denominator so that equal values compare equal field by field.
+///
+/// Results that do not fit in `i64` are rounded to a nearby fraction that
+/// does, so arithmetic never overflows.
+///
+/// Serialized as a string such as `"3/4"`, or `"2"` for whole numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rational {
+    num: i64,
+    den: i64,
+}
+
+impl Rational {
+    pub const ZERO: Rational = Rational { num: 0, den: 1 };
+    pub const ONE: Rational = Rational { num: 1, den: 1 };
+
+    /// Panics if `den` is zero.
+    pub fn new(num: i64, den: i64) -> Self {
+        Self::reduce(num as i128, den as i128)
+    }
+
+    /// Panics if `den` is zero.
+    fn reduce(num: i128, den: i128) -> Self {
+        assert!(den != 0, "zero denominator");
+        let sign = if den < 0 { -1 } else { 1 };
+        let divisor = gcd(num, den).max(1);
+        let (num, den) = (sign * num / divisor, sign * den / divisor);
+        match (i64::try_from(num), i64::try_from(den)) {
+            (Ok(num), Ok(den)) => Rational { num, den },
+            _ => Self::approximate(num, den),
+        }
+    }
+
+    /// The last continued fraction convergent of `num / den` (with `den`
+    /// positive) whose terms both fit in `i64`.
+    fn approximate(num: i128, den: i128) -> Self {
+        let (mut p, mut q) = (num, den);
+        let (mut h0, mut h1) = (0i128, 1i128);
+        let (mut k0, mut k1) = (1i128, 0i128);
+        while q != 0 {
+            let a = p.div_euclid(q);
+            let (h, k) = (
+                a.checked_mul(h1).and_then(|v| v.checked_add(h0)),
+                a.checked_mul(k1).and_then(|v| v.checked_add(k0)),
+            );
+            let (Some(h), Some(k)) = (h, k) else { break };
+            if i64::try_from(h).is_err() || i64::try_from(k).is_err() {
+                break;
+            }
+            (h0, h1, k0, k1) = (h1, h, k1, k);
+            (p, q) = (q, p.rem_euclid(q));
+        }
+        if k1 == 0 {
+            // Not even the integer part fits
+            let num = if num < 0 { i64::MIN + 1 } else { i64::MAX };
+            return Rational { num, den: 1 };
+        }
+        Rational {
+            num: h1 as i64,
+            den: k1 as i64,
+        }
+    }
+
+    pub fn numer(&self) -> i64 {
+        self.num
+    }
+
+    pub fn denom(&self) -> i64 {
+        self.den
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    /// Numerator and denominator widened so that sums and products of two
+    /// of them cannot overflow.
+    fn wide(self) -> (i128, i128) {
+        (self.num as i128, self.den as i128)
+    }
+}
+
+impl Default for Rational {
+    fn default() -> Self {
+        Rational::ZERO
+    }
+}
+
+impl From<i64> for Rational {
+    fn from(n: i64) -> Self {
+        Rational { num: n, den: 1 }
+    }
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+impl Add for Rational {
+    type Output = Rational;
+
+    fn add(self, rhs: Rational) -> Rational {
+        let (a, b) = (self.wide(), rhs.wide());
+        let den = a.1 / gcd(a.1, b.1) * b.1;
+        Rational::reduce(a.0 * (den / a.1) + b.0 * (den / b.1), den)
+    }
+}
+
+impl Sub for Rational {
+    type Output = Rational;
+
+    fn sub(self, rhs: Rational) -> Rational {
+        let (a, b) = (self.wide(), rhs.wide());
+        let den = a.1 / gcd(a.1, b.1) * b.1;
+        Rational::reduce(a.0 * (den / a.1) - b.0 * (den / b.1), den)
+    }
+}
+
+impl Mul for Rational {
+    type Output = Rational;
+
+    fn mul(self, rhs: Rational) -> Rational {
+        let ((n1, d1), (n2, d2)) = (self.wide(), rhs.wide());
+        Rational::reduce(n1 * n2, d1 * d2)
+    }
+}
+
+impl Div for Rational {
+    type Output = Rational;
+
+    /// Panics if `rhs` is zero.
+    fn div(self, rhs: Rational) -> Rational {
+        let ((n1, d1), (n2, d2)) = (self.wide(), rhs.wide());
+        Rational::reduce(n1 * d2, d1 * n2)
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.num as i128 * other.den as i128).cmp(&(other.num as i128 * self.den as i128))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Display for Rational {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.den == 1 {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
+
+impl std::str::FromStr for Rational {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rational: {}", s);
+        let (num, den) = s.split_once('/').unwrap_or((s, "1"));
+        let num = num.trim().parse().map_err(|_| invalid())?;
+        let den = den.trim().parse().map_err(|_| invalid())?;
+        if den == 0 {
+            return Err(invalid());
+        }
+        Ok(Rational::new(num, den))
+    }
+}
+
+impl Serialize for Rational {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rational {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{Branch, Chart, Metadata, Note, TempoMap};
+use serde::{Deserialize, Serialize};
+
+/// How notes travel towards the judgement mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ScrollMode {
+    /// Every note moves at its own speed, set by its BPM and `#SCROLL`.
+    #[default]
+    Normal,
+    /// `#BMSCROLL`: notes are placed by beat, so tempo changes alter the
+    /// distance between them, `#DELAY` stops the lane and `#SCROLL` is
+    /// ignored.
+    BmScroll,
+    /// `#HBSCROLL`: like `#BMSCROLL`, with `#SCROLL` applied on top.
+    HbScroll,
+}
+
+impl ScrollMode {
+    pub fn is_normal(&self) -> bool {
+        *self == ScrollMode::Normal
+    }
+}
+
+impl Chart {
+    /// Distance of `note` from the judgement mark at song time `time`, in
+    /// beats: 4.0 is the length of a 4/4 measure at scroll 1. The distance
+    /// is positive while the note is approaching and negative once it has
+    /// passed.
+    ///
+    /// `note` is looked up in the chart to find its branch; a note from
+    /// elsewhere is treated as part of the normal branch. To place many
+    /// notes, build a [`Chart::playfield`] once instead.
+    pub fn note_position(&self, metadata: &Metadata, note: &Note, time: f64) -> f64 {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.notes.iter().any(|n| std::ptr::eq(n, note)));
+        self.playfield(metadata).note_position(
+            segment.and_then(|s| s.branch.as_deref()),
+            note,
+            time,
+        )
+    }
+
+    /// [`Chart::note_position`] of every note at `time`, in the order of
+    /// `segments` and their `notes`.
+    pub fn note_positions(&self, metadata: &Metadata, time: f64) -> Vec<f64> {
+        self.playfield(metadata).note_positions(time)
+    }
+
+    /// Prepares the tempo of every branch for placing notes, to be reused
+    /// from frame to frame.
+    pub fn playfield(&self, metadata: &Metadata) -> Playfield<'_> {
+        let maps = match self.scroll_mode {
+            ScrollMode::Normal => None,
+            _ => Some(
+                [Branch::Normal, Branch::Expert, Branch::Master]
+                    .map(|branch| TempoMap::new(self, metadata, branch)),
+            ),
+        };
+        Playfield { chart: self, maps }
+    }
+}
+
+/// Places the notes of a chart on the lane, see [`Chart::playfield`].
+#[derive(Debug, Clone)]
+pub struct Playfield<'a> {
+    chart: &'a Chart,
+    /// Tempo maps of the normal, expert and master branches, only needed
+    /// when notes are placed by beat.
+    maps: Option<[TempoMap; 3]>,
+}
+
+impl Playfield<'_> {
+    /// [`Chart::note_position`] of a note of the segment on `branch`.
+    pub fn note_position(&self, branch: Option<&str>, note: &Note, time: f64) -> f64 {
+        let Some(maps) = &self.maps else {
+            return normal_position(note, time);
+        };
+        let now = maps[branch_index(branch)].time_to_beat(time);
+        self.beat_position(note, now)
+    }
+
+    /// [`Chart::note_positions`] at `time`.
+    pub fn note_positions(&self, time: f64) -> Vec<f64> {
+        let notes = self
+            .chart
+            .segments
+            .iter()
+            .flat_map(|segment| segment.notes.iter().map(move |note| (segment, note)));
+        let Some(maps) = &self.maps else {
+            return notes.map(|(_, note)| normal_position(note, time)).collect();
+        };
+        let now = maps.each_ref().map(|map| map.time_to_beat(time));
+        notes
+            .map(|(segment, note)| {
+                self.beat_position(note, now[branch_index(segment.branch.as_deref())])
+            })
+            .collect()
+    }
+
+    fn beat_position(&self, note: &Note, now: f64) -> f64 {
+        let distance = note.beat.to_f64() - now;
+        match self.chart.scroll_mode {
+            ScrollMode::HbScroll => distance * note.scroll,
+            _ => distance,
+        }
+    }
+}
+
+fn normal_position(note: &Note, time: f64) -> f64 {
+    (note.timestamp - time) * note.bpm / 60.0 * note.scroll
+}
+
+fn branch_index(branch: Option<&str>) -> usize {
+    match branch.and_then(|b| b.parse().ok()) {
+        Some(Branch::Expert) => 1,
+        Some(Branch::Master) => 2,
+        _ => 0,
+    }
+}
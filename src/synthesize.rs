@@ -1,4 +1,4 @@
-use crate::{Chart, Course, NoteType, ParsedTJA};
+use crate::{Branch, BranchProfile, Chart, Course, NoteType, ParsedTJA};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -103,21 +103,20 @@ fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
     resampled
 }
 
+/// Collects the notes played on `branch` (`"N"`, `"E"` or `"M"`), which is
+/// taken at every `#BRANCHSTART`. Without a branch the normal one is used,
+/// as at the start of a play.
 pub fn filter_notes(course_data: &Chart, branch: Option<&str>) -> Vec<FilteredNote> {
+    let branch = branch
+        .and_then(|b| b.parse().ok())
+        .unwrap_or(Branch::Normal);
+    let course_data = &course_data.flatten_branches(&BranchProfile::Forced(branch));
     let mut filtered_notes = Vec::new();
 
     // Pre-collect all EndOf timestamps in order for efficient lookup
     // This avoids O(n*m) nested loop searching for EndOf notes
     let mut end_of_timestamps: Vec<f64> = Vec::new();
     for segment in &course_data.segments {
-        // Skip if branch doesn't match
-        if let Some(branch_name) = branch {
-            if let Some(segment_branch) = &segment.branch {
-                if segment_branch != branch_name {
-                    continue;
-                }
-            }
-        }
         for note in &segment.notes {
             if matches!(note.note_type, NoteType::EndOf) {
                 end_of_timestamps.push(note.timestamp);
@@ -127,18 +126,9 @@ pub fn filter_notes(course_data: &Chart, branch: Option<&str>) -> Vec<FilteredNo
     let mut end_of_iter = end_of_timestamps.iter().peekable();
 
     for segment in &course_data.segments {
-        // Skip if branch doesn't match
-        if let Some(branch_name) = branch {
-            if let Some(segment_branch) = &segment.branch {
-                if segment_branch != branch_name {
-                    continue;
-                }
-            }
-        }
-
         for note in &segment.notes {
             match note.note_type {
-                NoteType::Roll | NoteType::RollBig | NoteType::Balloon | NoteType::BalloonAlt => {
+                ref roll if roll.is_roll() => {
                     // Find the next EndOf note timestamp that comes after this note
                     // Advance the iterator past any EndOf timestamps that are before or at the current note
                     while let Some(&&end_time) = end_of_iter.peek() {
@@ -150,14 +140,10 @@ pub fn filter_notes(course_data: &Chart, branch: Option<&str>) -> Vec<FilteredNo
 
                     if let Some(&&end_time) = end_of_iter.peek() {
                         let duration = end_time - note.timestamp;
-                        let filtered_type = match note.note_type {
-                            NoteType::Roll | NoteType::RollBig => {
-                                FilteredNoteType::DrumRoll { duration }
-                            }
-                            NoteType::Balloon | NoteType::BalloonAlt => {
-                                FilteredNoteType::Balloon { duration }
-                            }
-                            _ => unreachable!(),
+                        let filtered_type = if note.note_type.is_balloon() {
+                            FilteredNoteType::Balloon { duration }
+                        } else {
+                            FilteredNoteType::DrumRoll { duration }
                         };
                         filtered_notes.push(FilteredNote {
                             note_type: filtered_type,
@@ -172,18 +158,28 @@ pub fn filter_notes(course_data: &Chart, branch: Option<&str>) -> Vec<FilteredNo
                         );
                     }
                 }
-                NoteType::Don | NoteType::DonBig => {
+                NoteType::Don | NoteType::DonBig | NoteType::DonBoth => {
                     filtered_notes.push(FilteredNote {
                         note_type: FilteredNoteType::Don,
                         timestamp: note.timestamp,
                     });
                 }
-                NoteType::Ka | NoteType::KaBig => {
+                NoteType::Ka | NoteType::KaBig | NoteType::KaBoth => {
                     filtered_notes.push(FilteredNote {
                         note_type: FilteredNoteType::Ka,
                         timestamp: note.timestamp,
                     });
                 }
+                // Kadon is hit with a don and a ka at once
+                NoteType::Purple => {
+                    for note_type in [FilteredNoteType::Don, FilteredNoteType::Ka] {
+                        filtered_notes.push(FilteredNote {
+                            note_type,
+                            timestamp: note.timestamp,
+                        });
+                    }
+                }
+                // Bombs are avoided and ADLib notes are optional
                 _ => {}
             }
         }
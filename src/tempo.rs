@@ -0,0 +1,228 @@
+use crate::{Branch, Chart, Directive, Measure, Metadata, Rational};
+
+/// A stretch of the chart at one tempo, see [`TempoMap::segments`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoSegment {
+    /// Start in seconds, including `#DELAY`.
+    pub time: f64,
+    /// Start in quarter notes since `#START`, as in [`crate::Note::beat`].
+    pub beat: Rational,
+    /// 0 while a `#DELAY` holds the chart.
+    pub bpm: f64,
+}
+
+/// Conversion between song time and beats along one branch of a chart,
+/// built from `BPM`, `OFFSET` and the chart's `#BPMCHANGE`, `#MEASURE` and
+/// `#DELAY` directives.
+///
+/// Times are derived the way the parser times notes, so the
+/// [`TempoMap::exact_time`] of a note's `beat` is its `timestamp`. After a
+/// `#BRANCHEND`, the chart goes on from the state at the end of the last
+/// branch written in the block, and so does the map of every branch.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    /// Beat, time without delays and BPM of every tempo, ordered by beat.
+    tempos: Vec<(Rational, f64, f64)>,
+    /// Beat and total delay from that beat on, ordered by beat.
+    delays: Vec<(Rational, f64)>,
+    segments: Vec<TempoSegment>,
+    measures: Vec<Measure>,
+}
+
+impl TempoMap {
+    /// The tempo map of `branch` of `chart`. Unbranched parts of the chart
+    /// are shared by every branch.
+    pub fn new(chart: &Chart, metadata: &Metadata, branch: Branch) -> Self {
+        let on_branch = |b: &Option<String>| b.as_deref().is_none_or(|b| b == branch.as_str());
+        let measures: Vec<Measure> = chart
+            .measures
+            .iter()
+            .filter(|m| on_branch(&m.branch))
+            .cloned()
+            .collect();
+
+        let mut map = Self {
+            tempos: vec![(Rational::ZERO, -metadata.offset, metadata.bpm)],
+            delays: Vec::new(),
+            segments: Vec::new(),
+            measures: Vec::new(),
+        };
+        let mut delay = 0.0;
+        let mut next = 0;
+        for event in chart.events.iter().filter(|e| on_branch(&e.branch)) {
+            // A measure starts once the directives on its first beat apply
+            while let Some(measure) = measures
+                .get(next)
+                .filter(|m| (m.measure_index, m.beat) < (event.measure_index, event.beat))
+            {
+                map.follow(measure, &mut delay);
+                next += 1;
+            }
+            match event.directive {
+                Directive::BpmChange(bpm) => map.set_bpm(event.beat, bpm),
+                Directive::Delay(seconds) => {
+                    delay += seconds;
+                    map.set_delay(event.beat, delay);
+                }
+                _ => {}
+            }
+        }
+        for measure in &measures[next..] {
+            map.follow(measure, &mut delay);
+        }
+
+        map.measures = measures;
+        map.segments = map.build_segments();
+        map
+    }
+
+    fn set_bpm(&mut self, beat: Rational, bpm: f64) {
+        // Changes on the same beat only keep the last one, as the parser
+        // only sees the tempo of the next note
+        if self.tempos.len() > 1 && self.tempos.last().is_some_and(|t| t.0 == beat) {
+            self.tempos.pop();
+        }
+        let &(start, time, current) = self.tempos.last().unwrap();
+        if bpm != current {
+            let time = time + (beat - start).to_f64() * 60.0 / current;
+            self.tempos.push((beat, time, bpm));
+        }
+    }
+
+    fn set_delay(&mut self, beat: Rational, delay: f64) {
+        if self.delays.last().is_some_and(|d| d.0 == beat) {
+            self.delays.pop();
+        }
+        self.delays.push((beat, delay));
+    }
+
+    /// Restarts the map from `measure` where the parser timed it on
+    /// another timeline, as after a `#BRANCHEND` whose last branch is not
+    /// this one, or with [`crate::ParserOptions::legacy_delay`].
+    fn follow(&mut self, measure: &Measure, delay: &mut f64) {
+        if measure.delay != *delay {
+            *delay = measure.delay;
+            self.set_delay(measure.beat, *delay);
+        }
+        // Measures serialized by earlier versions have no tempo
+        if measure.bpm == 0.0 {
+            return;
+        }
+        let time = measure.timestamp - measure.delay;
+        if self.tempo_at(measure.beat).2 != measure.bpm
+            || (self.tempo_time(measure.beat) - time).abs() > 1e-9
+        {
+            if self.tempos.len() > 1 && self.tempos.last().is_some_and(|t| t.0 == measure.beat) {
+                self.tempos.pop();
+            }
+            self.tempos.push((measure.beat, time, measure.bpm));
+        }
+    }
+
+    fn build_segments(&self) -> Vec<TempoSegment> {
+        let mut beats: Vec<Rational> = self
+            .tempos
+            .iter()
+            .map(|t| t.0)
+            .chain(self.delays.iter().map(|d| d.0))
+            .collect();
+        beats.sort();
+        beats.dedup();
+
+        let mut segments = Vec::with_capacity(beats.len() * 2);
+        for beat in beats {
+            let before = self.delay_before(beat);
+            let after = self.delay_at(beat);
+            if after > before {
+                segments.push(TempoSegment {
+                    time: self.tempo_time(beat) + before,
+                    beat,
+                    bpm: 0.0,
+                });
+            }
+            segments.push(TempoSegment {
+                time: self.exact_time(beat),
+                beat,
+                bpm: self.tempo_at(beat).2,
+            });
+        }
+        segments
+    }
+
+    /// Every tempo and `#DELAY` along the branch, ordered by beat. A
+    /// negative `#DELAY` has no segment of its own; the next segment simply
+    /// starts earlier than the previous one ends.
+    pub fn segments(&self) -> &[TempoSegment] {
+        &self.segments
+    }
+
+    /// Time of an exact beat. Notes on that beat have this timestamp.
+    pub fn exact_time(&self, beat: Rational) -> f64 {
+        self.tempo_time(beat) + self.delay_at(beat)
+    }
+
+    pub fn beat_to_time(&self, beat: f64) -> f64 {
+        let index = self.segments.partition_point(|s| s.beat.to_f64() <= beat);
+        let segment = self.running(index.saturating_sub(1));
+        segment.time + (beat - segment.beat.to_f64()) * 60.0 / segment.bpm
+    }
+
+    /// Beat reached at `time`. The beat stands still during `#DELAY`, and
+    /// where a negative `#DELAY` makes times overlap the later beat is used.
+    pub fn time_to_beat(&self, time: f64) -> f64 {
+        let segment = match self.segments.iter().rposition(|s| s.time <= time) {
+            Some(index) => &self.segments[index],
+            None => self.running(0),
+        };
+        segment.beat.to_f64() + (time - segment.time) * segment.bpm / 60.0
+    }
+
+    /// Tempo in effect at `time`. During a `#DELAY`, the tempo before it.
+    pub fn bpm_at(&self, time: f64) -> f64 {
+        self.segments
+            .iter()
+            .rev()
+            .find(|s| s.time <= time && s.bpm != 0.0)
+            .unwrap_or_else(|| self.running(0))
+            .bpm
+    }
+
+    /// The measure being played at `time`, if any.
+    pub fn measure_at(&self, time: f64) -> Option<&Measure> {
+        let index = self.measures.partition_point(|m| m.timestamp <= time);
+        self.measures
+            .get(index.checked_sub(1)?)
+            .filter(|m| time < m.end)
+    }
+
+    /// The first segment from `index` on that is not a delay. Every delay
+    /// is followed by one.
+    fn running(&self, index: usize) -> &TempoSegment {
+        self.segments[index..]
+            .iter()
+            .find(|s| s.bpm != 0.0)
+            .unwrap_or(&self.segments[0])
+    }
+
+    fn tempo_at(&self, beat: Rational) -> (Rational, f64, f64) {
+        let index = self.tempos.partition_point(|t| t.0 <= beat);
+        self.tempos[index.saturating_sub(1)]
+    }
+
+    fn tempo_time(&self, beat: Rational) -> f64 {
+        let (start, time, bpm) = self.tempo_at(beat);
+        time + (beat - start).to_f64() * 60.0 / bpm
+    }
+
+    /// Total delay of notes on `beat`.
+    fn delay_at(&self, beat: Rational) -> f64 {
+        let index = self.delays.partition_point(|d| d.0 <= beat);
+        index.checked_sub(1).map_or(0.0, |i| self.delays[i].1)
+    }
+
+    /// Total delay just before `beat`.
+    fn delay_before(&self, beat: Rational) -> f64 {
+        let index = self.delays.partition_point(|d| d.0 < beat);
+        index.checked_sub(1).map_or(0.0, |i| self.delays[i].1)
+    }
+}
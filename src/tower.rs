@@ -0,0 +1,70 @@
+use crate::{Branch, Chart};
+use serde::{Deserialize, Serialize};
+
+/// One floor of a tower chart, see [`Chart::floors`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Floor {
+    /// Counted from 1.
+    pub number: usize,
+    pub start: f64,
+    pub end: f64,
+    /// Index of the first measure of the floor, counted from `#START`.
+    pub measure_index: usize,
+    /// Number of measures making up the floor.
+    pub measures: usize,
+    /// Notes judged on the floor, see [`crate::NoteType::is_hit`].
+    pub notes: usize,
+}
+
+impl Chart {
+    /// Splits a tower chart into floors. Every measure with a bar line
+    /// starts a new floor, while measures after `#BARLINEOFF` add to the
+    /// current one. Measures that only contain rests count as well.
+    ///
+    /// Branched measures are only counted on the normal branch; use
+    /// [`Chart::flatten_branches`] first to follow another path.
+    pub fn floors(&self) -> Vec<Floor> {
+        let measures = self
+            .measures
+            .iter()
+            .filter(|measure| on_normal_path(measure.branch.as_deref()));
+
+        let mut floors: Vec<Floor> = Vec::new();
+        for measure in measures {
+            match floors.last_mut() {
+                Some(floor) if !measure.barline => {
+                    floor.end = measure.end;
+                    floor.measures += 1;
+                }
+                _ => floors.push(Floor {
+                    number: floors.len() + 1,
+                    start: measure.timestamp,
+                    end: measure.end,
+                    measure_index: measure.measure_index,
+                    measures: 1,
+                    notes: 0,
+                }),
+            }
+        }
+
+        // Notes delayed past the end of the chart stay on the top floor
+        let top = floors.len().saturating_sub(1);
+        let notes = self
+            .segments
+            .iter()
+            .filter(|segment| on_normal_path(segment.branch.as_deref()))
+            .flat_map(|segment| &segment.notes)
+            .filter(|note| note.note_type.is_hit());
+        for note in notes {
+            let index = floors.partition_point(|floor| floor.end <= note.timestamp);
+            if let Some(floor) = floors.get_mut(index.min(top)) {
+                floor.notes += 1;
+            }
+        }
+        floors
+    }
+}
+
+fn on_normal_path(branch: Option<&str>) -> bool {
+    branch.is_none_or(|b| b == Branch::Normal.as_str())
+}
@@ -1,10 +1,20 @@
+use crate::dan::{Exam, MAX_EXAMS};
+use crate::directives::Directive;
+use crate::error::{Location, SourceRange};
+use crate::rational::Rational;
+use crate::scroll::ScrollMode;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 fn is_false(b: &bool) -> bool {
     !*b
 }
 
+fn is_zero(x: &f64) -> bool {
+    *x == 0.0
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NoteType {
     Empty,      // "0"
@@ -17,6 +27,15 @@ pub enum NoteType {
     Balloon,    // "7"
     EndOf,      // "8"
     BalloonAlt, // "9"
+    // Extended notes, with `ParserOptions::extended_notes`
+    DonBoth,  // "A", big don hit with both hands together
+    KaBoth,   // "B", big ka hit with both hands together
+    Bomb,     // "C", must not be hit
+    FuseRoll, // "D", balloon that explodes if not cleared
+    Adlib,    // "F", hidden bonus note
+    Purple,   // "G", kadon: don and ka together
+    RollBigH, // "H", big roll variant
+    RollBigI, // "I", big roll variant
 }
 
 impl NoteType {
@@ -36,6 +55,91 @@ impl NoteType {
         }
     }
 
+    /// Like [`NoteType::from_char`], also accepting the letters of the
+    /// extended notes as with [`crate::ParserOptions::extended_notes`].
+    pub fn from_char_extended(c: char) -> Option<Self> {
+        match c {
+            'A'..='I' => Self::from_extended_byte(c as u8),
+            c => Self::from_char(c),
+        }
+    }
+
+    pub fn to_char(&self) -> char {
+        match self {
+            NoteType::Empty => '0',
+            NoteType::Don => '1',
+            NoteType::Ka => '2',
+            NoteType::DonBig => '3',
+            NoteType::KaBig => '4',
+            NoteType::Roll => '5',
+            NoteType::RollBig => '6',
+            NoteType::Balloon => '7',
+            NoteType::EndOf => '8',
+            NoteType::BalloonAlt => '9',
+            NoteType::DonBoth => 'A',
+            NoteType::KaBoth => 'B',
+            NoteType::Bomb => 'C',
+            NoteType::FuseRoll => 'D',
+            NoteType::Adlib => 'F',
+            NoteType::Purple => 'G',
+            NoteType::RollBigH => 'H',
+            NoteType::RollBigI => 'I',
+        }
+    }
+
+    /// Maps the letters of [`NoteType::DonBoth`] and the other extended
+    /// notes, which only some simulators support.
+    #[inline]
+    pub fn from_extended_byte(b: u8) -> Option<Self> {
+        match b {
+            b'A' => Some(NoteType::DonBoth),
+            b'B' => Some(NoteType::KaBoth),
+            b'C' => Some(NoteType::Bomb),
+            b'D' => Some(NoteType::FuseRoll),
+            b'F' => Some(NoteType::Adlib),
+            b'G' => Some(NoteType::Purple),
+            b'H' => Some(NoteType::RollBigH),
+            b'I' => Some(NoteType::RollBigI),
+            _ => None,
+        }
+    }
+
+    /// Notes that last until the next [`NoteType::EndOf`].
+    pub fn is_roll(&self) -> bool {
+        matches!(
+            self,
+            NoteType::Roll
+                | NoteType::RollBig
+                | NoteType::Balloon
+                | NoteType::BalloonAlt
+                | NoteType::FuseRoll
+                | NoteType::RollBigH
+                | NoteType::RollBigI
+        )
+    }
+
+    /// Notes that take a count from `BALLOON:`.
+    pub fn is_balloon(&self) -> bool {
+        matches!(
+            self,
+            NoteType::Balloon | NoteType::BalloonAlt | NoteType::FuseRoll
+        )
+    }
+
+    /// Notes judged good, ok or bad, which make up the accuracy.
+    pub fn is_hit(&self) -> bool {
+        matches!(
+            self,
+            NoteType::Don
+                | NoteType::Ka
+                | NoteType::DonBig
+                | NoteType::KaBig
+                | NoteType::DonBoth
+                | NoteType::KaBoth
+                | NoteType::Purple
+        )
+    }
+
     /// Faster version that works directly with ASCII bytes (0-9)
     #[inline]
     pub fn from_byte(b: u8) -> Option<Self> {
@@ -62,6 +166,10 @@ pub enum Course {
     Hard,
     Oni,
     Ura,
+    /// Tower mode: measures are climbed as floors, see [`Chart::floors`].
+    Tower,
+    /// Dan-i dojo: several songs in a row, see [`Chart::songs`].
+    Dan,
 }
 
 impl std::str::FromStr for Course {
@@ -74,6 +182,8 @@ impl std::str::FromStr for Course {
             "HARD" | "2" => Ok(Course::Hard),
             "ONI" | "3" => Ok(Course::Oni),
             "URA" | "EDIT" | "4" => Ok(Course::Ura),
+            "TOWER" | "5" => Ok(Course::Tower),
+            "DAN" | "6" => Ok(Course::Dan),
             _ => Err(format!("Invalid course: {}", s)),
         }
     }
@@ -98,6 +208,90 @@ impl std::str::FromStr for Level {
     }
 }
 
+/// Locale suffixes of `TITLE` and `SUBTITLE`, as in `TITLEJA`.
+pub const TITLE_LOCALES: &[&str] = &["JA", "EN", "CN", "TW", "ZH", "KO"];
+
+/// Whether a subtitle is shown on song select, from its `--` or `++` prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SubtitleVisibility {
+    /// No prefix; left to the simulator.
+    Unspecified,
+    /// `--`: only shown during play.
+    Hidden,
+    /// `++`: shown on song select as well.
+    Visible,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Subtitle {
+    /// The subtitle without its prefix.
+    pub text: String,
+    pub visibility: SubtitleVisibility,
+}
+
+impl std::str::FromStr for Subtitle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (text, visibility) = if let Some(text) = s.strip_prefix("--") {
+            (text, SubtitleVisibility::Hidden)
+        } else if let Some(text) = s.strip_prefix("++") {
+            (text, SubtitleVisibility::Visible)
+        } else {
+            (s, SubtitleVisibility::Unspecified)
+        };
+        Ok(Subtitle {
+            text: text.to_string(),
+            visibility,
+        })
+    }
+}
+
+/// Scoring system selected by `SCOREMODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScoreMode {
+    /// `0`: arcade generations 1 to 7.
+    Ac1To7,
+    /// `1`: arcade generations 8 to 14.
+    Ac8To14,
+    /// `2`: the newer arcade cabinets (shin-uchi).
+    ShinUchi,
+}
+
+impl std::str::FromStr for ScoreMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(ScoreMode::Ac1To7),
+            "1" => Ok(ScoreMode::Ac8To14),
+            "2" => Ok(ScoreMode::ShinUchi),
+            _ => Err(format!("Invalid score mode: {}", s)),
+        }
+    }
+}
+
+/// Which song select side a song appears on, from `SIDE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Normal,
+    Ex,
+    Both,
+}
+
+impl std::str::FromStr for Side {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "NORMAL" | "1" => Ok(Side::Normal),
+            "EX" | "2" => Ok(Side::Ex),
+            "BOTH" | "3" => Ok(Side::Both),
+            _ => Err(format!("Invalid side: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Metadata {
     pub bpm: f64,
@@ -105,7 +299,41 @@ pub struct Metadata {
     pub demostart: f64,
     pub songvol: i32,
     pub sevol: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Localized titles by lowercase locale, e.g. `"ja"` for `TITLEJA`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub titles: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subtitle: Option<Subtitle>,
+    /// Localized subtitles by lowercase locale, e.g. `"ja"` for `SUBTITLEJA`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub subtitles: HashMap<String, Subtitle>,
+    /// Audio file, relative to the TJA file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wave: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maker: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scoremode: Option<ScoreMode>,
+    /// Song select image, relative to the TJA file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<String>,
+    /// Background video, relative to the TJA file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bgmovie: Option<String>,
+    /// Lyrics file, relative to the TJA file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lyrics: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side: Option<Side>,
     pub raw: HashMap<String, String>,
+    /// Keys of `raw` the parser did not recognise, with
+    /// [`crate::ParserOptions::retain_unknown_headers`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unknown_keys: Vec<String>,
 }
 
 impl Metadata {
@@ -131,19 +359,81 @@ impl Metadata {
             .and_then(|s| s.parse().ok())
             .unwrap_or(100);
 
+        // Empty values are treated as missing
+        let text = |key: &str| data.get(key).filter(|s| !s.is_empty()).cloned();
+        let localized = |key: &str| -> HashMap<String, String> {
+            TITLE_LOCALES
+                .iter()
+                .filter_map(|locale| {
+                    text(&format!("{}{}", key, locale)).map(|s| (locale.to_lowercase(), s))
+                })
+                .collect()
+        };
+        let parse_subtitle = |s: String| s.parse::<Subtitle>().ok();
+
         Self {
+            title: text("TITLE"),
+            titles: localized("TITLE"),
+            subtitle: text("SUBTITLE").and_then(parse_subtitle),
+            subtitles: localized("SUBTITLE")
+                .into_iter()
+                .filter_map(|(locale, s)| Some((locale, parse_subtitle(s)?)))
+                .collect(),
+            wave: text("WAVE"),
+            genre: text("GENRE"),
+            maker: text("MAKER"),
+            scoremode: data.get("SCOREMODE").and_then(|s| s.parse().ok()),
+            preimage: text("PREIMAGE"),
+            bgmovie: text("BGMOVIE"),
+            lyrics: text("LYRICS"),
+            side: data.get("SIDE").and_then(|s| s.parse().ok()),
             raw: data,
             bpm,
             offset,
             demostart,
             songvol,
             sevol,
+            unknown_keys: Vec::new(),
         }
     }
 
     pub fn get(&self, key: &str) -> Option<&String> {
         self.raw.get(key)
     }
+
+    /// Title for `locale` (e.g. `"ja"`), falling back to a related Chinese
+    /// variant and then to `TITLE`.
+    pub fn title_for(&self, locale: &str) -> Option<&str> {
+        localized(&self.titles, locale)
+            .or(self.title.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Subtitle for `locale` (e.g. `"ja"`), with the same fallback as
+    /// [`Metadata::title_for`].
+    pub fn subtitle_for(&self, locale: &str) -> Option<&Subtitle> {
+        localized(&self.subtitles, locale).or(self.subtitle.as_ref())
+    }
+
+    /// Resolves `WAVE` against the TJA file at `tja_path`.
+    pub fn wave_path<P: AsRef<Path>>(&self, tja_path: P) -> Option<PathBuf> {
+        let wave = self.wave.as_ref()?;
+        let dir = tja_path.as_ref().parent().unwrap_or(Path::new(""));
+        Some(dir.join(wave))
+    }
+}
+
+fn localized<'a, T>(values: &'a HashMap<String, T>, locale: &str) -> Option<&'a T> {
+    let locale = locale.to_lowercase();
+    // Simplified and traditional Chinese titles stand in for each other
+    let related: &[&str] = match locale.as_str() {
+        "zh" => &["cn", "tw"],
+        "cn" | "tw" => &["zh"],
+        _ => &[],
+    };
+    std::iter::once(locale.as_str())
+        .chain(related.iter().copied())
+        .find_map(|locale| values.get(locale))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -153,8 +443,34 @@ pub struct Note {
     pub bpm: f64,
     pub delay: f64,
     pub scroll: f64,
-    #[serde(skip_serializing_if = "is_false")]
+    /// Imaginary part of a complex `#SCROLL`, which moves the note
+    /// vertically.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub scroll_imag: f64,
+    #[serde(default, skip_serializing_if = "is_false")]
     pub gogo: bool,
+    /// Index of the measure the note is in, as in [`ChartEvent::measure_index`].
+    #[serde(default)]
+    pub measure_index: usize,
+    /// Number of characters of the measure, counting rests, or 0 for notes
+    /// not read from a TJA file.
+    #[serde(default)]
+    pub subdivision: usize,
+    /// Index of the note's character among the `subdivision` of its measure.
+    #[serde(default)]
+    pub slot: usize,
+    /// Exact position within the measure, from 0 (its start) to 1 (its end),
+    /// i.e. `slot / subdivision`.
+    #[serde(default)]
+    pub position: Rational,
+    /// Exact beat since `#START` in quarter notes, not counting `#DELAY`.
+    /// `timestamp` is derived from it. Branches restart from the beat of
+    /// their `#BRANCHSTART`.
+    #[serde(default)]
+    pub beat: Rational,
+    /// Position of the note character, with [`crate::ParserOptions::source_spans`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -165,7 +481,15 @@ pub struct Segment {
     pub barline: bool,
     pub branch: Option<String>,
     pub branch_condition: Option<String>,
+    /// Index of the `#BRANCHSTART` block the measure belongs to, counted
+    /// from 0 within the chart. See [`Chart::branch_sections`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch_section: Option<usize>,
     pub notes: Vec<Note>,
+    /// From the first character of the measure to its comma, with
+    /// [`crate::ParserOptions::source_spans`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<SourceRange>,
 }
 
 impl Segment {
@@ -184,7 +508,9 @@ impl Segment {
             barline,
             branch,
             branch_condition,
+            branch_section: None,
             notes: Vec::new(),
+            span: None,
         }
     }
 
@@ -193,6 +519,55 @@ impl Segment {
     }
 }
 
+/// A directive placed on the chart's timeline.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChartEvent {
+    /// Time at which the directive takes effect, including `#DELAY`.
+    pub timestamp: f64,
+    /// Exact beat at which the directive takes effect, as in [`Note::beat`].
+    #[serde(default)]
+    pub beat: Rational,
+    /// Index of the measure the directive appears in, counted from `#START`.
+    /// Branches restart from the index of their `#BRANCHSTART`.
+    pub measure_index: usize,
+    /// Position within the measure, from 0 (its start) to 1 (its end).
+    pub position: f64,
+    pub branch: Option<String>,
+    pub directive: Directive,
+}
+
+/// A measure as written between two commas, kept in every parsing mode,
+/// including measures that only contain rests.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Measure {
+    /// Start of the measure, including `#DELAY`.
+    pub timestamp: f64,
+    /// Start of the following measure.
+    pub end: f64,
+    /// Exact beat at which the measure starts, as in [`Note::beat`].
+    #[serde(default)]
+    pub beat: Rational,
+    /// Counted from `#START`, as in [`ChartEvent::measure_index`].
+    pub measure_index: usize,
+    pub measure_num: i32,
+    pub measure_den: i32,
+    pub barline: bool,
+    /// Whether the measure starts in go-go time.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub gogo: bool,
+    /// Tempo at the start of the measure, 0 for measures serialized by
+    /// earlier versions.
+    #[serde(default)]
+    pub bpm: f64,
+    /// Total `#DELAY` included in `timestamp`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub delay: f64,
+    pub branch: Option<String>,
+    /// Index of the `#BRANCHSTART` block the measure belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch_section: Option<usize>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chart {
     pub player: i32,
@@ -201,6 +576,31 @@ pub struct Chart {
     pub balloons: Vec<i32>,
     pub headers: HashMap<String, String>,
     pub segments: Vec<Segment>,
+    /// Every measure of the chart, in source order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub measures: Vec<Measure>,
+    /// Pass conditions of a dan course, from `EXAM1` on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exams: Vec<Exam>,
+    /// Lives of a tower course, from `LIFE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub life: Option<u32>,
+    /// From `SIDE`, either in the course headers or before the first course.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side: Option<Side>,
+    /// Set by `#BMSCROLL` or `#HBSCROLL`.
+    #[serde(default, skip_serializing_if = "ScrollMode::is_normal")]
+    pub scroll_mode: ScrollMode,
+    /// Keys of `headers` the parser did not recognise, with
+    /// [`crate::ParserOptions::retain_unknown_headers`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unknown_headers: Vec<String>,
+    /// Every directive of the chart, in source order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<ChartEvent>,
+    /// From `#START` to `#END`, with [`crate::ParserOptions::source_spans`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<SourceRange>,
 }
 
 impl Chart {
@@ -217,6 +617,13 @@ impl Chart {
             })
             .unwrap_or_default();
 
+        let exams = (1..=MAX_EXAMS)
+            .filter_map(|n| headers.get(&format!("EXAM{}", n)))
+            .filter_map(|s| s.parse().ok())
+            .collect();
+        let life = headers.get("LIFE").and_then(|s| s.parse().ok());
+        let side = headers.get("SIDE").and_then(|s| s.parse().ok());
+
         Self {
             player,
             course,
@@ -224,6 +631,14 @@ impl Chart {
             balloons,
             headers,
             segments: Vec::new(),
+            measures: Vec::new(),
+            exams,
+            life,
+            side,
+            scroll_mode: ScrollMode::Normal,
+            unknown_headers: Vec::new(),
+            events: Vec::new(),
+            span: None,
         }
     }
 
@@ -234,6 +649,36 @@ impl Chart {
     pub fn level(&self) -> Option<i32> {
         self.level.as_ref().map(|l| l.value())
     }
+
+    /// The bar line at the start of every measure, including measures that
+    /// only contain rests, in source order. Branches each have their own.
+    pub fn barlines(&self) -> Vec<Barline> {
+        self.measures
+            .iter()
+            .map(|measure| Barline {
+                timestamp: measure.timestamp,
+                beat: measure.beat,
+                measure_index: measure.measure_index,
+                branch: measure.branch.clone(),
+                visible: measure.barline,
+                gogo: measure.gogo,
+            })
+            .collect()
+    }
+}
+
+/// A bar line, see [`Chart::barlines`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Barline {
+    pub timestamp: f64,
+    pub beat: Rational,
+    pub measure_index: usize,
+    pub branch: Option<String>,
+    /// Hidden by `#BARLINEOFF`. `#BARLINEOFF` and `#BARLINEON` in the middle
+    /// of a measure only apply from the next bar line.
+    pub visible: bool,
+    /// Whether the bar line is in go-go time.
+    pub gogo: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1,5 +1,5 @@
 use crate::synthesize::{synthesize_tja_audio, AudioData};
-use crate::{Course, ParsingMode, TJAParser};
+use crate::{Course, ParserOptions, ParsingMode, TJAParser};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
@@ -29,6 +29,8 @@ pub enum WasmCourse {
     Hard,
     Oni,
     Ura,
+    Tower,
+    Dan,
 }
 
 impl From<WasmCourse> for Course {
@@ -39,6 +41,8 @@ impl From<WasmCourse> for Course {
             WasmCourse::Hard => Course::Hard,
             WasmCourse::Oni => Course::Oni,
             WasmCourse::Ura => Course::Ura,
+            WasmCourse::Tower => Course::Tower,
+            WasmCourse::Dan => Course::Dan,
         }
     }
 }
@@ -65,11 +69,25 @@ impl From<WasmAudioData> for AudioData {
 }
 
 #[wasm_bindgen]
-pub fn parse_tja(content: &str, mode: Option<WasmParsingMode>) -> Result<JsValue, JsValue> {
-    let mut parser = TJAParser::with_mode(mode.unwrap_or(WasmParsingMode::Full).into());
+pub fn parse_tja(
+    content: &str,
+    mode: Option<WasmParsingMode>,
+    source_spans: Option<bool>,
+    retain_unknown_headers: Option<bool>,
+    extended_notes: Option<bool>,
+    legacy_delay: Option<bool>,
+) -> Result<JsValue, JsValue> {
+    let options = ParserOptions {
+        source_spans: source_spans.unwrap_or(false),
+        retain_unknown_headers: retain_unknown_headers.unwrap_or(false),
+        extended_notes: extended_notes.unwrap_or(false),
+        legacy_delay: legacy_delay.unwrap_or(false),
+        ..Default::default()
+    };
+    let mut parser = TJAParser::with_options(mode.unwrap_or(WasmParsingMode::Full).into(), options);
     parser
         .parse_str(content)
-        .map_err(|e| JsValue::from_str(&e))?;
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
 
     let parsed = parser.get_parsed_tja();
     serde_wasm_bindgen::to_value(&parsed).map_err(|e| JsValue::from_str(&e.to_string()))
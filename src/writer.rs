@@ -0,0 +1,777 @@
+use crate::{
+    Chart, Course, Directive, Measure, Metadata, NextSong, Note, NoteType, ParsedTJA, ScrollMode,
+    Segment, SubtitleVisibility,
+};
+use std::collections::{HashMap, VecDeque};
+
+/// Metadata keys in the order they are conventionally written.
+pub(crate) const METADATA_ORDER: &[&str] = &[
+    "TITLE",
+    "TITLEJA",
+    "TITLEEN",
+    "TITLECN",
+    "TITLETW",
+    "TITLEZH",
+    "TITLEKO",
+    "SUBTITLE",
+    "SUBTITLEJA",
+    "SUBTITLEEN",
+    "SUBTITLECN",
+    "SUBTITLETW",
+    "SUBTITLEZH",
+    "SUBTITLEKO",
+    "BPM",
+    "WAVE",
+    "OFFSET",
+    "DEMOSTART",
+    "GENRE",
+    "MAKER",
+    "SONGVOL",
+    "SEVOL",
+    "SCOREMODE",
+    "SIDE",
+    "LIFE",
+    "PREIMAGE",
+    "BGMOVIE",
+    "LYRICS",
+];
+
+/// Course header keys in the order they are conventionally written.
+pub(crate) const HEADER_ORDER: &[&str] = &[
+    "COURSE",
+    "LEVEL",
+    "BALLOON",
+    "SCOREINIT",
+    "SCOREDIFF",
+    "STYLE",
+    "LIFE",
+    "SIDE",
+    "EXAM1",
+    "EXAM2",
+    "EXAM3",
+    "EXAM4",
+    "EXAM5",
+    "EXAM6",
+    "EXAM7",
+    "EXAM8",
+    "EXAM9",
+];
+
+/// Largest subdivision tried when looking for a measure's resolution.
+const MAX_RESOLUTION: usize = 1536;
+
+/// Allowed distance of a note from its slot, in slots.
+const SLOT_TOLERANCE: f64 = 1e-3;
+
+/// Serializes a parsed file back to TJA text.
+///
+/// Measures are written at the smallest subdivision that places every note
+/// on a slot, so re-parsing the output yields the same note timestamps up to
+/// floating point error.
+pub fn write_tja(parsed: &ParsedTJA) -> String {
+    let mut out = String::new();
+    write_pairs(&mut out, &parsed.metadata.raw, METADATA_ORDER);
+    for chart in &parsed.charts {
+        out.push('\n');
+        out.push_str(&write_chart(chart, &parsed.metadata));
+    }
+    out
+}
+
+/// Serializes a single chart, including its course headers.
+///
+/// Every measure of [`Chart::measures`] is written, including those that
+/// only contain rests. Directives come from [`Chart::events`], in the
+/// measure and at the position they were read; tempo, scroll speed, go-go
+/// time, delay, time signature and bar lines are also written wherever the
+/// notes and measures disagree with them. Charts without measures, such as
+/// generated ones, have their rests restored from the gaps between segments.
+pub fn write_chart(chart: &Chart, metadata: &Metadata) -> String {
+    let mut writer = ChartWriter::new(metadata);
+    write_pairs(&mut writer.out, &chart.headers, HEADER_ORDER);
+    let mode_events = chart.events.iter().any(|event| {
+        matches!(
+            event.directive,
+            Directive::BmScroll | Directive::HbScroll | Directive::NmScroll
+        )
+    });
+    match chart.scroll_mode {
+        ScrollMode::BmScroll if !mode_events => writer.line("#BMSCROLL"),
+        ScrollMode::HbScroll if !mode_events => writer.line("#HBSCROLL"),
+        _ => {}
+    }
+    writer.out.push('\n');
+    match chart.player {
+        1 => writer.line("#START P1"),
+        2 => writer.line("#START P2"),
+        _ => writer.line("#START"),
+    }
+    writer.events = chart
+        .events
+        .iter()
+        .map(|event| EventLine {
+            branch: event.branch.clone(),
+            measure_index: event.measure_index,
+            position: event.position,
+            line: directive_line(&event.directive),
+            directive: &event.directive,
+        })
+        .collect();
+    writer.skip_markers();
+    if chart.measures.is_empty() {
+        for segment in &chart.segments {
+            writer.enter_branch(
+                segment.branch.as_deref(),
+                segment.branch_condition.as_ref(),
+                segment.branch_section,
+            );
+            writer.write_segment(segment);
+        }
+    } else {
+        let conditions: Vec<&String> = chart
+            .events
+            .iter()
+            .filter_map(|event| match &event.directive {
+                Directive::BranchStart(condition) => Some(condition),
+                _ => None,
+            })
+            .collect();
+        let mut segments = chart.segments.iter().peekable();
+        for measure in &chart.measures {
+            let segment = segments.next_if(|s| {
+                s.branch == measure.branch
+                    && s.branch_section == measure.branch_section
+                    && s.notes
+                        .first()
+                        .is_none_or(|n| n.measure_index == measure.measure_index)
+            });
+            let condition = segment
+                .and_then(|s| s.branch_condition.as_ref())
+                .or_else(|| conditions.get(measure.branch_section?).copied());
+            writer.enter_branch(measure.branch.as_deref(), condition, measure.branch_section);
+            writer.write_measure(measure, segment);
+        }
+    }
+    writer.finish_branch();
+    if writer.branch.is_some() {
+        writer.line("#BRANCHEND");
+    }
+    // Events of branches without measures
+    while let Some(event) = writer.events.pop_front() {
+        writer.event(&event);
+    }
+    writer.line("#END");
+    writer.out
+}
+
+/// Text of the directives [`write_chart`] takes from [`Chart::events`],
+/// `None` for those it writes from the chart's structure.
+fn directive_line(directive: &Directive) -> Option<String> {
+    Some(match directive {
+        Directive::BpmChange(bpm) => format!("#BPMCHANGE {}", bpm),
+        Directive::Scroll(scroll) => format!("#SCROLL {}", scroll),
+        Directive::ComplexScroll(real, imag) => format!("#SCROLL {}", complex(*real, *imag)),
+        Directive::GogoStart => "#GOGOSTART".to_string(),
+        Directive::GogoEnd => "#GOGOEND".to_string(),
+        Directive::Delay(delay) => format!("#DELAY {}", delay),
+        Directive::Measure(num, den) => format!("#MEASURE {}/{}", num, den),
+        Directive::BarlineOff => "#BARLINEOFF".to_string(),
+        Directive::BarlineOn => "#BARLINEON".to_string(),
+        Directive::Section => "#SECTION".to_string(),
+        Directive::LevelHold => "#LEVELHOLD".to_string(),
+        Directive::BmScroll => "#BMSCROLL".to_string(),
+        Directive::HbScroll => "#HBSCROLL".to_string(),
+        Directive::NmScroll => "#NMSCROLL".to_string(),
+        Directive::JposScroll {
+            duration,
+            distance,
+            distance_imag,
+            to_right,
+        } => format!(
+            "#JPOSSCROLL {} {} {}",
+            duration,
+            complex(*distance, *distance_imag),
+            u8::from(*to_right)
+        ),
+        Directive::Direction(direction) => format!("#DIRECTION {}", direction),
+        Directive::Sudden { appear, moving } => format!("#SUDDEN {} {}", appear, moving),
+        Directive::NextSong(song) => format!("#NEXTSONG {}", next_song_fields(song)),
+        Directive::Raw { name, args } => with_args(name, args),
+        // Handlers receive their arguments as text, which is what string
+        // data usually holds
+        Directive::Custom { name, data } => match data {
+            serde_json::Value::String(args) => with_args(name, args),
+            serde_json::Value::Null => with_args(name, ""),
+            data => with_args(name, &data.to_string()),
+        },
+        _ => return None,
+    })
+}
+
+fn with_args(name: &str, args: &str) -> String {
+    match args {
+        "" => format!("#{}", name),
+        args => format!("#{} {}", name, args),
+    }
+}
+
+/// The arguments of `#NEXTSONG`, without trailing empty fields.
+fn next_song_fields(song: &NextSong) -> String {
+    let subtitle = song.subtitle.as_ref().map(|subtitle| {
+        let prefix = match subtitle.visibility {
+            SubtitleVisibility::Unspecified => "",
+            SubtitleVisibility::Hidden => "--",
+            SubtitleVisibility::Visible => "++",
+        };
+        format!("{}{}", prefix, subtitle.text)
+    });
+    let course = song.course.as_ref().map(|course| {
+        match course {
+            Course::Easy => "Easy",
+            Course::Normal => "Normal",
+            Course::Hard => "Hard",
+            Course::Oni => "Oni",
+            Course::Ura => "Edit",
+            Course::Tower => "Tower",
+            Course::Dan => "Dan",
+        }
+        .to_string()
+    });
+    let mut fields = vec![
+        Some(song.title.clone()),
+        subtitle,
+        song.genre.clone(),
+        song.wave.clone(),
+        song.scoreinit.map(|v| v.to_string()),
+        song.scorediff.map(|v| v.to_string()),
+        song.level.map(|v| v.to_string()),
+        course,
+    ];
+    while fields.last().is_some_and(Option::is_none) {
+        fields.pop();
+    }
+    fields
+        .into_iter()
+        .map(Option::unwrap_or_default)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A real or complex number as `#SCROLL` and `#JPOSSCROLL` read it.
+fn complex(real: f64, imag: f64) -> String {
+    match imag {
+        0.0 => real.to_string(),
+        imag if imag < 0.0 => format!("{}{}i", real, imag),
+        imag => format!("{}+{}i", real, imag),
+    }
+}
+
+impl ParsedTJA {
+    pub fn to_tja_string(&self) -> String {
+        write_tja(self)
+    }
+}
+
+fn write_pairs(out: &mut String, pairs: &HashMap<String, String>, order: &[&str]) {
+    let mut keys: Vec<_> = pairs.keys().collect();
+    keys.sort_by_key(|key| {
+        let rank = order.iter().position(|k| k == key).unwrap_or(order.len());
+        (rank, key.as_str())
+    });
+    for key in keys {
+        out.push_str(&format!("{}:{}\n", key, pairs[key]));
+    }
+}
+
+fn branch_rank(branch: &str) -> u8 {
+    match branch {
+        "N" => 0,
+        "E" => 1,
+        _ => 2,
+    }
+}
+
+/// Mirrors the parser state so that directives are only written where the
+/// parsed values change.
+struct ChartWriter<'a> {
+    out: String,
+    time: f64,
+    bpm: f64,
+    scroll: f64,
+    scroll_imag: f64,
+    gogo: bool,
+    barline: bool,
+    measure: (i32, i32),
+    delay: f64,
+    branch: Option<String>,
+    branch_condition: Option<String>,
+    branch_section: Option<usize>,
+    branch_start: BranchStart,
+    /// Index of the measure being written, as in [`Measure::measure_index`].
+    measure_index: usize,
+    /// Directives still to be written, in chart order.
+    events: VecDeque<EventLine<'a>>,
+}
+
+/// A directive of [`Chart::events`] waiting for its measure.
+struct EventLine<'a> {
+    branch: Option<String>,
+    measure_index: usize,
+    position: f64,
+    /// `None` for `#START`, `#END` and branch directives, which the writer
+    /// writes itself and which separate the directives of each branch.
+    line: Option<String>,
+    directive: &'a Directive,
+}
+
+/// The state every branch of a block restarts from, as in the parser.
+#[derive(Debug, Clone, Copy, Default)]
+struct BranchStart {
+    time: f64,
+    bpm: f64,
+    scroll: f64,
+    scroll_imag: f64,
+    gogo: bool,
+    barline: bool,
+    measure: (i32, i32),
+    delay: f64,
+    measure_index: usize,
+}
+
+impl<'a> ChartWriter<'a> {
+    fn new(metadata: &Metadata) -> Self {
+        Self {
+            out: String::new(),
+            time: -metadata.offset,
+            bpm: metadata.bpm,
+            scroll: 1.0,
+            scroll_imag: 0.0,
+            gogo: false,
+            barline: true,
+            measure: (4, 4),
+            delay: 0.0,
+            branch: None,
+            branch_condition: None,
+            branch_section: None,
+            branch_start: BranchStart::default(),
+            measure_index: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn measure_beats(&self) -> f64 {
+        4.0 * self.measure.0 as f64 / self.measure.1 as f64
+    }
+
+    /// Takes the directives of the measure being written, and those of
+    /// earlier measures that were not written.
+    fn take_events(&mut self) -> Vec<EventLine<'a>> {
+        let mut events = Vec::new();
+        while self.events.front().is_some_and(|e| {
+            e.line.is_some() && e.branch == self.branch && e.measure_index <= self.measure_index
+        }) {
+            events.extend(self.events.pop_front());
+        }
+        events
+    }
+
+    /// Drops the directives that lead to the current branch, up to the one
+    /// that enters it.
+    fn skip_markers(&mut self) {
+        while let Some(event) = self.events.pop_front() {
+            if event.line.is_some() {
+                self.events.push_front(event);
+                break;
+            }
+            let entered = matches!(
+                (event.directive, self.branch.as_deref()),
+                (Directive::Start(_) | Directive::BranchEnd, None)
+                    | (Directive::BranchNormal, Some("N"))
+                    | (Directive::BranchExpert, Some("E"))
+                    | (Directive::BranchMaster, Some("M"))
+            );
+            if entered {
+                break;
+            }
+        }
+    }
+
+    /// Writes a directive and applies it to the state.
+    fn event(&mut self, event: &EventLine) {
+        match *event.directive {
+            Directive::BpmChange(bpm) => self.bpm = bpm,
+            Directive::Scroll(scroll) => (self.scroll, self.scroll_imag) = (scroll, 0.0),
+            Directive::ComplexScroll(real, imag) => (self.scroll, self.scroll_imag) = (real, imag),
+            Directive::GogoStart => self.gogo = true,
+            Directive::GogoEnd => self.gogo = false,
+            Directive::Delay(delay) => self.delay += delay,
+            Directive::Measure(num, den) => self.measure = (num, den),
+            Directive::BarlineOff => self.barline = false,
+            Directive::BarlineOn => self.barline = true,
+            _ => {}
+        }
+        if let Some(line) = &event.line {
+            self.line(line);
+        }
+    }
+
+    /// Writes the directives left at the end of the current branch, or the
+    /// chart.
+    fn finish_branch(&mut self) {
+        while self
+            .events
+            .front()
+            .is_some_and(|e| e.line.is_some() && e.branch == self.branch)
+        {
+            let event = self.events.pop_front().unwrap();
+            self.event(&event);
+        }
+    }
+
+    fn set_signature(&mut self, measure: (i32, i32), barline: bool) {
+        if measure != self.measure {
+            self.line(&format!("#MEASURE {}/{}", measure.0, measure.1));
+            self.measure = measure;
+        }
+        if barline != self.barline {
+            self.line(if barline { "#BARLINEON" } else { "#BARLINEOFF" });
+            self.barline = barline;
+        }
+    }
+
+    /// Writes a measure of rests, with its directives.
+    fn empty_measure(&mut self) {
+        for event in self.take_events() {
+            self.event(&event);
+        }
+        self.line(",");
+        self.measure_index += 1;
+    }
+
+    fn enter_branch(
+        &mut self,
+        branch: Option<&str>,
+        condition: Option<&String>,
+        section: Option<usize>,
+    ) {
+        if branch == self.branch.as_deref()
+            && condition == self.branch_condition.as_ref()
+            && section == self.branch_section
+        {
+            return;
+        }
+        self.finish_branch();
+
+        let Some(branch) = branch else {
+            self.line("#BRANCHEND");
+            self.branch = None;
+            self.branch_condition = None;
+            self.branch_section = None;
+            self.skip_markers();
+            return;
+        };
+
+        // Branches of one block are written N, E, M; going back starts a new block
+        let new_block = match self.branch.as_deref() {
+            Some(current) => {
+                condition != self.branch_condition.as_ref()
+                    || section != self.branch_section
+                    || branch_rank(branch) <= branch_rank(current)
+            }
+            None => true,
+        };
+        if new_block {
+            self.line(&format!(
+                "#BRANCHSTART {}",
+                condition.map_or("", String::as_str)
+            ));
+            self.branch_start = BranchStart {
+                time: self.time,
+                bpm: self.bpm,
+                scroll: self.scroll,
+                scroll_imag: self.scroll_imag,
+                gogo: self.gogo,
+                barline: self.barline,
+                measure: self.measure,
+                delay: self.delay,
+                measure_index: self.measure_index,
+            };
+            self.branch_condition = condition.cloned();
+            self.branch_section = section;
+        }
+        self.line(&format!("#{}", branch));
+        self.branch = Some(branch.to_string());
+        self.skip_markers();
+        let start = self.branch_start;
+        self.time = start.time;
+        self.bpm = start.bpm;
+        self.scroll = start.scroll;
+        self.scroll_imag = start.scroll_imag;
+        self.gogo = start.gogo;
+        self.barline = start.barline;
+        self.measure = start.measure;
+        self.delay = start.delay;
+        self.measure_index = start.measure_index;
+    }
+
+    /// Writes rest measures until the clock reaches `start`.
+    fn fill_empty_measures(&mut self, start: f64) {
+        loop {
+            let duration = 60.0 / self.bpm * self.measure_beats();
+            let gap = start - self.time;
+            // A tempo of 0 stops the clock
+            if !duration.is_finite() || !gap.is_finite() || duration <= 0.0 || gap < duration / 2.0
+            {
+                break;
+            }
+            self.empty_measure();
+            self.time += duration;
+        }
+
+        // A shorter rest measure covers whatever is left
+        let beats = (start - self.time) * self.bpm / 60.0;
+        if let Some(measure) = measure_for(beats) {
+            self.line(&format!("#MEASURE {}/{}", measure.0, measure.1));
+            self.empty_measure();
+            self.measure = measure;
+            self.time = start;
+        }
+    }
+
+    /// Writes the directives needed before `note` and updates the state.
+    fn sync(&mut self, note: &Note, directives: &mut Vec<String>) {
+        if note.bpm != self.bpm {
+            directives.push(format!("#BPMCHANGE {}", note.bpm));
+            self.bpm = note.bpm;
+        }
+        if note.scroll != self.scroll || note.scroll_imag != self.scroll_imag {
+            directives.push(format!(
+                "#SCROLL {}",
+                complex(note.scroll, note.scroll_imag)
+            ));
+            self.scroll = note.scroll;
+            self.scroll_imag = note.scroll_imag;
+        }
+        if note.gogo != self.gogo {
+            directives.push(if note.gogo { "#GOGOSTART" } else { "#GOGOEND" }.to_string());
+            self.gogo = note.gogo;
+        }
+        if note.delay != self.delay {
+            directives.push(format!("#DELAY {}", note.delay - self.delay));
+            self.delay = note.delay;
+        }
+    }
+
+    fn differs(&self, note: &Note, previous: Option<&Note>) -> bool {
+        match previous {
+            Some(p) => {
+                note.bpm != p.bpm
+                    || note.scroll != p.scroll
+                    || note.scroll_imag != p.scroll_imag
+                    || note.gogo != p.gogo
+                    || note.delay != p.delay
+            }
+            None => {
+                note.bpm != self.bpm
+                    || note.scroll != self.scroll
+                    || note.scroll_imag != self.scroll_imag
+                    || note.gogo != self.gogo
+                    || note.delay != self.delay
+            }
+        }
+    }
+
+    /// Writes a segment of a chart without measures, after the rests
+    /// leading up to it.
+    fn write_segment(&mut self, segment: &Segment) {
+        let delay = segment.notes.first().map_or(self.delay, |note| note.delay);
+        let start = segment.timestamp - delay;
+        self.fill_empty_measures(start);
+        if let Some(note) = segment.notes.first() {
+            self.measure_index = self.measure_index.max(note.measure_index);
+        }
+        let signature = (segment.measure_num, segment.measure_den);
+        self.write_notes(&segment.notes, start, signature, segment.barline);
+    }
+
+    /// Writes a measure with the notes of its segment, if any.
+    fn write_measure(&mut self, measure: &Measure, segment: Option<&Segment>) {
+        self.measure_index = measure.measure_index;
+        let notes = segment.map_or(&[][..], |s| &s.notes);
+        let signature = (measure.measure_num, measure.measure_den);
+        self.write_notes(
+            notes,
+            measure.timestamp - measure.delay,
+            signature,
+            measure.barline,
+        );
+    }
+
+    fn write_notes(&mut self, notes: &[Note], start: f64, signature: (i32, i32), barline: bool) {
+        // Directives of earlier measures go first
+        let events = self.take_events();
+        let event_positions: Vec<f64> = events
+            .iter()
+            .map(|e| {
+                if e.measure_index < self.measure_index {
+                    0.0
+                } else {
+                    e.position
+                }
+            })
+            .collect();
+
+        // Position of every note as a fraction of the measure
+        let beats = 4.0 * signature.0 as f64 / signature.1 as f64;
+        let (mut time, mut bpm, mut beat) = (start, self.bpm, 0.0);
+        let mut positions = Vec::with_capacity(notes.len());
+        for note in notes {
+            let note_time = note.timestamp - note.delay;
+            beat += (note_time - time) * bpm / 60.0;
+            // Parsed notes know their slot; others are placed by time
+            positions.push(match note.subdivision {
+                0 => beat / beats,
+                n => note.slot as f64 / n as f64,
+            });
+            time = note_time;
+            bpm = note.bpm;
+        }
+        let end = time + (beats - beat) * 60.0 / bpm;
+
+        // Rests only matter when they carry a change of state
+        let mut kept: Vec<(&Note, f64)> = Vec::new();
+        for (note, &position) in notes.iter().zip(&positions) {
+            if note.note_type != NoteType::Empty || self.differs(note, kept.last().map(|k| k.0)) {
+                kept.push((note, position));
+            }
+        }
+
+        // Rests are only written to place directives within the measure
+        let (resolution, slots) = if kept.is_empty() && event_positions.iter().all(|&p| p <= 0.0) {
+            (0, Vec::new())
+        } else if beats > 0.0 {
+            layout(
+                &kept.iter().map(|k| k.1).collect::<Vec<_>>(),
+                &event_positions,
+            )
+        } else {
+            // Zero-length measures place every note at the same time
+            (kept.len().max(1), (0..kept.len()).collect())
+        };
+
+        let event_slots = event_positions
+            .iter()
+            .map(|p| (p * resolution as f64 - SLOT_TOLERANCE).ceil().max(0.0) as usize);
+        let mut events = events.into_iter().zip(event_slots).peekable();
+
+        // The state before the first note, directives included, is the
+        // measure's time signature and bar line
+        while let Some((event, _)) = events.next_if(|(_, s)| resolution == 0 || *s == 0) {
+            self.event(&event);
+        }
+        self.set_signature(signature, barline);
+
+        let mut pending = String::with_capacity(resolution + 1);
+        let mut kept_iter = kept.iter().zip(&slots).peekable();
+        for slot in 0..resolution {
+            if events.peek().is_some_and(|(_, s)| *s <= slot) && !pending.is_empty() {
+                self.line(&std::mem::take(&mut pending));
+            }
+            while let Some((event, _)) = events.next_if(|(_, s)| *s <= slot) {
+                self.event(&event);
+            }
+            match kept_iter.next_if(|(_, &s)| s == slot) {
+                Some(((note, _), _)) => {
+                    let mut directives = Vec::new();
+                    self.sync(note, &mut directives);
+                    if !directives.is_empty() {
+                        if !pending.is_empty() {
+                            self.line(&std::mem::take(&mut pending));
+                        }
+                        for directive in directives {
+                            self.line(&directive);
+                        }
+                    }
+                    pending.push(note.note_type.to_char());
+                }
+                None => pending.push('0'),
+            }
+        }
+        if events.peek().is_some() && !pending.is_empty() {
+            self.line(&std::mem::take(&mut pending));
+        }
+        for (event, _) in events {
+            self.event(&event);
+        }
+        pending.push(',');
+        self.line(&pending);
+        self.measure_index += 1;
+
+        self.time = if notes.is_empty() {
+            start + 60.0 / self.bpm * beats
+        } else {
+            end
+        };
+    }
+}
+
+/// Picks the smallest subdivision that puts every position on a slot of its
+/// own, returning it along with the slot of each position. Directives at
+/// `events` are put on a slot as well where possible.
+fn layout(positions: &[f64], events: &[f64]) -> (usize, Vec<usize>) {
+    let find = |events: &[f64]| {
+        (1..=MAX_RESOLUTION).find(|&n| {
+            fits(positions, n)
+                && events.iter().all(|&p| {
+                    let slot = p * n as f64;
+                    (slot - slot.round()).abs() < SLOT_TOLERANCE
+                })
+        })
+    };
+    let resolution = find(events).or_else(|| find(&[])).unwrap_or(MAX_RESOLUTION);
+    let n = resolution as f64;
+    let mut slots: Vec<usize> = Vec::with_capacity(positions.len());
+    for &p in positions {
+        let slot = (p * n).round().max(0.0) as usize;
+        // Never let two notes share a slot
+        let slot = match slots.last() {
+            Some(&prev) if slot <= prev => prev + 1,
+            _ => slot,
+        };
+        slots.push(slot);
+    }
+    let resolution = slots
+        .last()
+        .map_or(resolution, |&last| resolution.max(last + 1));
+    (resolution, slots)
+}
+
+/// Smallest `#MEASURE` signature spanning `beats` quarter notes.
+fn measure_for(beats: f64) -> Option<(i32, i32)> {
+    if beats < SLOT_TOLERANCE {
+        return None;
+    }
+    (1..=MAX_RESOLUTION as i32).find_map(|den| {
+        let num = beats * den as f64 / 4.0;
+        ((num - num.round()).abs() < SLOT_TOLERANCE).then_some((num.round() as i32, den))
+    })
+}
+
+fn fits(positions: &[f64], resolution: usize) -> bool {
+    let n = resolution as f64;
+    let mut previous = None;
+    positions.iter().all(|&p| {
+        let slot = (p * n).round();
+        let ok = (p * n - slot).abs() < SLOT_TOLERANCE
+            && slot >= 0.0
+            && slot < n
+            && previous.is_none_or(|prev| slot > prev);
+        previous = Some(slot);
+        ok
+    })
+}
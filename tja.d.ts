@@ -1,4 +1,11 @@
-export function parse_tja(content: string, mode?: WasmParsingMode): Promise<ParsedTJA>;
+export function parse_tja(
+    content: string,
+    mode?: WasmParsingMode,
+    source_spans?: boolean,
+    retain_unknown_headers?: boolean,
+    extended_notes?: boolean,
+    legacy_delay?: boolean,
+): Promise<ParsedTJA>;
 
 export enum WasmParsingMode {
     MetadataOnly = 0,
@@ -14,11 +21,89 @@ export interface ParsedTJA {
 
 export interface Chart {
     player: number;
-    course?: "Easy" | "Normal" | "Hard" | "Oni" | "Ura";
+    course?: "Easy" | "Normal" | "Hard" | "Oni" | "Ura" | "Tower" | "Dan";
     level?: number;
     balloons: number[];
     headers: Record<string, string>;
+    unknown_headers?: string[];
     segments: Segment[];
+    measures?: Measure[];
+    exams?: Exam[];
+    life?: number;
+    side?: "Normal" | "Ex" | "Both";
+    scroll_mode?: "Normal" | "BmScroll" | "HbScroll";
+    events: ChartEvent[];
+    span?: SourceRange;
+}
+
+export interface ChartEvent {
+    timestamp: number;
+    beat: string;
+    measure_index: number;
+    position: number;
+    branch?: string;
+    directive: Directive;
+}
+
+export type Directive =
+    | { Start: string | null }
+    | "End"
+    | { BpmChange: number }
+    | { Scroll: number }
+    | "GogoStart"
+    | "GogoEnd"
+    | "BarlineOff"
+    | "BarlineOn"
+    | { BranchStart: string }
+    | "BranchEnd"
+    | { Measure: [number, number] }
+    | { Delay: number }
+    | "Section"
+    | "LevelHold"
+    | "BmScroll"
+    | "HbScroll"
+    | "NmScroll"
+    | { ComplexScroll: [number, number] }
+    | { JposScroll: { duration: number; distance: number; distance_imag: number; to_right: boolean } }
+    | { Direction: number }
+    | { Sudden: { appear: number; moving: number } }
+    | { NextSong: NextSong }
+    | "BranchNormal"
+    | "BranchMaster"
+    | "BranchExpert"
+    | { Raw: { name: string; args: string } }
+    | { Custom: { name: string; data: unknown } };
+
+export interface NextSong {
+    title: string;
+    subtitle?: { text: string; visibility: "Unspecified" | "Hidden" | "Visible" } | null;
+    genre?: string | null;
+    wave?: string | null;
+    scoreinit?: number | null;
+    scorediff?: number | null;
+    level?: number | null;
+    course?: Chart["course"] | null;
+}
+
+export interface Exam {
+    kind: "Gauge" | "Good" | "Ok" | "Bad" | "Score" | "Roll" | "Hit" | "Combo";
+    red: number;
+    gold: number;
+    comparison: "AtLeast" | "LessThan";
+    scope: "Whole" | { PerSong: { red: number[]; gold: number[] } };
+}
+
+export interface Measure {
+    timestamp: number;
+    end: number;
+    beat: string;
+    measure_index: number;
+    measure_num: number;
+    measure_den: number;
+    barline: boolean;
+    gogo?: boolean;
+    branch?: string;
+    branch_section?: number;
 }
 
 export interface Segment {
@@ -28,14 +113,34 @@ export interface Segment {
     barline: boolean;
     branch?: string;
     branch_condition?: string;
+    branch_section?: number;
     notes: Note[];
+    span?: SourceRange;
 }
 
 export interface Note {
-    note_type: "Empty" | "Don" | "Ka" | "DonBig" | "KaBig" | "Roll" | "RollBig" | "Balloon" | "EndOf" | "BalloonAlt";
+    note_type: "Empty" | "Don" | "Ka" | "DonBig" | "KaBig" | "Roll" | "RollBig" | "Balloon" | "EndOf" | "BalloonAlt"
+        | "DonBoth" | "KaBoth" | "Bomb" | "FuseRoll" | "Adlib" | "Purple" | "RollBigH" | "RollBigI";
     timestamp: number;
     scroll: number;
+    scroll_imag?: number;
     delay: number;
     bpm: number;
     gogo: boolean;
+    measure_index: number;
+    subdivision: number;
+    slot: number;
+    position: string;
+    beat: string;
+    location?: Location;
+}
+
+export interface Location {
+    line: number;
+    column: number;
+}
+
+export interface SourceRange {
+    start: Location;
+    end: Location;
 }
@@ -1,9 +1,11 @@
 from enum import Enum
-from typing import Any, Dict, List, Optional, Literal
+from typing import Any, Dict, List, Optional, Literal, Tuple
 
-PyChartCourse = Literal["Easy", "Normal", "Hard", "Oni", "Ura"]
+PyChartCourse = Literal["Easy", "Normal", "Hard", "Oni", "Ura", "Tower", "Dan"]
+PySpan = Tuple[Tuple[int, int], Tuple[int, int]]
 PyNoteType = Literal[
-    "Don", "Ka", "DonBig", "KaBig", "Roll", "RollBig", "Balloon", "EndOf", "BalloonAlt"
+    "Don", "Ka", "DonBig", "KaBig", "Roll", "RollBig", "Balloon", "EndOf", "BalloonAlt",
+    "DonBoth", "KaBoth", "Bomb", "FuseRoll", "Adlib", "Purple", "RollBigH", "RollBigI"
 ]
 
 class PyParsingMode(Enum):
@@ -20,6 +22,8 @@ class PyCourse(Enum):
     Hard = "Hard"
     Oni = "Oni"
     Ura = "Ura"
+    Tower = "Tower"
+    Dan = "Dan"
     def __str__(self) -> str: ...
     def __repr__(self) -> str: ...
 
@@ -27,9 +31,16 @@ class PyNote:
     note_type: PyNoteType
     timestamp: float
     scroll: float
+    scroll_imag: float
     delay: float
     bpm: float
     gogo: bool
+    measure_index: int
+    subdivision: int
+    slot: int
+    position: str
+    beat: str
+    location: Optional[Tuple[int, int]]
     def __str__(self) -> str: ...
     def __repr__(self) -> str: ...
     def export(self) -> Dict[str, Any]: ...
@@ -41,7 +52,9 @@ class PySegment:
     barline: bool
     branch: Optional[str]
     branch_condition: Optional[str]
+    branch_section: Optional[int]
     notes: List[PyNote]
+    span: Optional[PySpan]
     def __str__(self) -> str: ...
     def __repr__(self) -> str: ...
     def export(self) -> Dict[str, Any]: ...
@@ -52,7 +65,12 @@ class PyChart:
     level: Optional[int]
     balloons: List[int]
     headers: Dict[str, str]
+    unknown_headers: List[str]
     segments: List[PySegment]
+    life: Optional[int]
+    side: Optional[Literal["Normal", "Ex", "Both"]]
+    scroll_mode: Literal["Normal", "BmScroll", "HbScroll"]
+    span: Optional[PySpan]
     def __str__(self) -> str: ...
     def __repr__(self) -> str: ...
     def export(self) -> Dict[str, Any]: ...
@@ -83,7 +101,12 @@ class PyParsedTJA:
     ) -> PyAudioData: ...
 
 def parse_tja(
-    content: str, mode: PyParsingMode = PyParsingMode.Full
+    content: str,
+    mode: PyParsingMode = PyParsingMode.Full,
+    source_spans: bool = False,
+    retain_unknown_headers: bool = False,
+    extended_notes: bool = False,
+    legacy_delay: bool = False,
 ) -> PyParsedTJA: ...
 def synthesize_tja_audio_py(
     tja: PyParsedTJA,